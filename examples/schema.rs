@@ -3,7 +3,14 @@ use std::fs::create_dir_all;
 
 use cosmwasm_schema::{export_schema, remove_schemas, schema_for};
 
-use orderbook_escrow::msg::{ExecuteMsg, InstantiateMsg, OrderResponse, QueryMsg, ReceiveMsg};
+use orderbook_escrow::msg::{
+    ActiveAssetsResponse, AssetKindResponse, BestOrderResponse, CapabilitiesResponse,
+    CloseFundsResponse, DepthResponse, ExecuteMsg, FillableCountResponse, InstantiateMsg,
+    IsBlacklistedResponse, MakerCallbackMsg, MigrateMsg, OracleQueryMsg, OrderMetaResponse,
+    OrderResponse, OrdersResponse, QueryMsg, RawOrderCountResponse, ReceiveMsg, SearchResponse,
+    SettlementPreviewResponse, SimulateOpenResponse, StatsResponse, TakerCallbackMsg, TwapResponse,
+    VaultMsg,
+};
 
 fn main() {
     let mut out_dir = current_dir().unwrap();
@@ -12,8 +19,29 @@ fn main() {
     remove_schemas(&out_dir).unwrap();
 
     export_schema(&schema_for!(InstantiateMsg), &out_dir);
+    export_schema(&schema_for!(MigrateMsg), &out_dir);
     export_schema(&schema_for!(ExecuteMsg), &out_dir);
     export_schema(&schema_for!(QueryMsg), &out_dir);
     export_schema(&schema_for!(ReceiveMsg), &out_dir);
     export_schema(&schema_for!(OrderResponse), &out_dir);
+    export_schema(&schema_for!(OrderMetaResponse), &out_dir);
+    export_schema(&schema_for!(SimulateOpenResponse), &out_dir);
+    export_schema(&schema_for!(ActiveAssetsResponse), &out_dir);
+    export_schema(&schema_for!(AssetKindResponse), &out_dir);
+    export_schema(&schema_for!(CloseFundsResponse), &out_dir);
+    export_schema(&schema_for!(SettlementPreviewResponse), &out_dir);
+    export_schema(&schema_for!(BestOrderResponse), &out_dir);
+    export_schema(&schema_for!(DepthResponse), &out_dir);
+    export_schema(&schema_for!(OrdersResponse), &out_dir);
+    export_schema(&schema_for!(SearchResponse), &out_dir);
+    export_schema(&schema_for!(TwapResponse), &out_dir);
+    export_schema(&schema_for!(FillableCountResponse), &out_dir);
+    export_schema(&schema_for!(StatsResponse), &out_dir);
+    export_schema(&schema_for!(CapabilitiesResponse), &out_dir);
+    export_schema(&schema_for!(RawOrderCountResponse), &out_dir);
+    export_schema(&schema_for!(IsBlacklistedResponse), &out_dir);
+    export_schema(&schema_for!(MakerCallbackMsg), &out_dir);
+    export_schema(&schema_for!(TakerCallbackMsg), &out_dir);
+    export_schema(&schema_for!(VaultMsg), &out_dir);
+    export_schema(&schema_for!(OracleQueryMsg), &out_dir);
 }
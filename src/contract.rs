@@ -1,76 +1,590 @@
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    from_binary, to_binary, Addr, BankMsg, Binary, Deps, DepsMut, Env, MessageInfo, Response,
-    StdResult, SubMsg, WasmMsg,
+    attr, from_binary, to_binary, Addr, Api, BankMsg, Binary, Coin, Decimal, Deps, DepsMut, Env,
+    Event, MessageInfo, Reply, Response, StdError, StdResult, Storage, SubMsg, Uint128, WasmMsg,
 };
-use cw2::set_contract_version;
-use cw20::{Balance, Cw20CoinVerified, Cw20ExecuteMsg, Cw20ReceiveMsg};
+use cw2::{get_contract_version, set_contract_version};
+use cw20::{
+    AllowanceResponse, Balance, BalanceResponse, Cw20CoinVerified, Cw20ExecuteMsg, Cw20QueryMsg,
+    Cw20ReceiveMsg, TokenInfoResponse,
+};
+use cw_storage_plus::{Bound, U64Key};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::{TryFrom, TryInto};
 
-use crate::error::ContractError;
-use crate::msg::{ExecuteMsg, InstantiateMsg, OpenOrderMsg, OrderResponse, QueryMsg, ReceiveMsg};
-use crate::state::{next_id, GenericBalance, Order, ORDERS};
+use crate::error::{ContractError, OrderInvalidReason};
+use crate::helpers::validate_addrs;
+use crate::msg::{
+    ActiveAssetsResponse, AssetKindResponse, BestOrderResponse, CapabilitiesResponse,
+    CloseFundsResponse, CloseOrderMsg, DepthLevel, DepthResponse, ExecuteMsg,
+    FillableCountResponse, InstantiateMsg, IsBlacklistedResponse, MakerCallbackMsg, MigrateMsg,
+    OpenOrderMsg, OraclePriceResponse, OracleQueryMsg, OrderMetaResponse, OrderResponse,
+    OrdersResponse, QueryMsg, RawOrderCountResponse, ReceiveMsg, SearchResponse,
+    SettlementPreviewResponse, SettlementRecipient, SettlementTransfer, SimulateOpenResponse,
+    StatsResponse, TakerCallbackMsg, TwapResponse, VaultMsg,
+};
+use crate::state::{
+    checked_ratio, implied_price, next_id, orders, AssetRef, Config, GenericBalance, Order,
+    OrderMeta, PendingDeposit, TwapObservation, BACKFILL_CURSOR, CONFIG, LAST_OPEN,
+    MAX_TWAP_OBSERVATIONS, ORDER_COUNT, ORDER_META, PENDING_DEPOSITS, STRANDED, TWAP, VOLUME,
+};
 
 // version info for migration info
 const CONTRACT_NAME: &str = "crates.io:orderbook-escrow";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+const MAKER_CALLBACK_REPLY_ID: u64 = 1;
+
+// settings for pagination
+const MAX_LIMIT: u32 = 30;
+const DEFAULT_LIMIT: u32 = 10;
+// upper bound on the orders a single query visits, matching or not
+const MAX_SCAN: usize = 100;
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
     deps: DepsMut,
     _env: Env,
-    _info: MessageInfo,
-    _msg: InstantiateMsg,
+    info: MessageInfo,
+    msg: InstantiateMsg,
 ) -> Result<Response, ContractError> {
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
-    // no setup
+    let config = Config {
+        owner: info.sender,
+        open_cooldown_blocks: msg.open_cooldown_blocks,
+        denom_aliases: validate_aliases(deps.api, msg.denom_aliases.unwrap_or_default())?,
+        asset_decimals: msg.asset_decimals.unwrap_or_default(),
+        blacklist: vec![],
+        oracle: None,
+        min_prices: vec![],
+    };
+    CONFIG.save(deps.storage, &config)?;
     Ok(Response::default())
 }
 
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(deps: DepsMut, _env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
+    let stored = get_contract_version(deps.storage)?;
+    if stored.contract != CONTRACT_NAME {
+        return Err(ContractError::WrongContract {});
+    }
+    if parse_version(&stored.version)? > parse_version(CONTRACT_VERSION)? {
+        return Err(ContractError::Downgrade {});
+    }
+
+    // The first versions kept no config, which opening and closing orders now load
+    if CONFIG.may_load(deps.storage)?.is_none() {
+        let owner = msg.owner.ok_or(ContractError::MissingOwner {})?;
+        let config = Config {
+            owner: deps.api.addr_validate(&owner)?,
+            open_cooldown_blocks: None,
+            denom_aliases: vec![],
+            asset_decimals: vec![],
+            blacklist: vec![],
+            oracle: None,
+            min_prices: vec![],
+        };
+        CONFIG.save(deps.storage, &config)?;
+    }
+
+    // Re-saving a large book here could exceed the block gas limit, so the indexes are
+    // backfilled in batches afterwards
+    let backfill_pending = ORDER_COUNT.may_load(deps.storage)?.unwrap_or_default() > 0;
+    if backfill_pending {
+        BACKFILL_CURSOR.save(deps.storage, &0)?;
+    }
+
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+    Ok(Response::new()
+        .add_attribute("method", "migrate")
+        .add_attribute("from_version", stored.version)
+        .add_attribute("to_version", CONTRACT_VERSION)
+        .add_attribute("backfill_pending", backfill_pending.to_string()))
+}
+
+pub fn execute_backfill_orders(
+    deps: DepsMut,
+    limit: Option<u32>,
+) -> Result<Response, ContractError> {
+    let cursor = BACKFILL_CURSOR
+        .may_load(deps.storage)?
+        .ok_or(ContractError::NothingToBackfill {})?;
+    let limit = limit.map_or(MAX_SCAN, |limit| (limit as usize).clamp(1, MAX_SCAN));
+    let start = Some(Bound::exclusive(U64Key::from(cursor)));
+    let batch: Vec<(Vec<u8>, Order)> = orders()
+        .range(deps.storage, start, None, cosmwasm_std::Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<_>>()?;
+
+    // Saving an order again fills in indexes added since it was stored
+    let mut last_saved = cursor;
+    for (key, order) in &batch {
+        last_saved = parse_order_id(key)?;
+        orders().save(deps.storage, last_saved.into(), order)?;
+    }
+    let done = batch.len() < limit;
+    if done {
+        BACKFILL_CURSOR.remove(deps.storage);
+    } else {
+        BACKFILL_CURSOR.save(deps.storage, &last_saved)?;
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "backfill_orders")
+        .add_attribute("backfilled", batch.len().to_string())
+        .add_attribute("done", done.to_string()))
+}
+
+/// Splits a `major.minor.patch[-pre]` version into a key that compares in version order,
+/// a pre-release sorting before its release
+fn parse_version(version: &str) -> StdResult<(Vec<u64>, bool, String)> {
+    let (core, pre) = match version.split_once('-') {
+        Some((core, pre)) => (core, Some(pre)),
+        None => (version, None),
+    };
+    let numbers = core
+        .split('.')
+        .map(|part| {
+            part.parse()
+                .map_err(|_| StdError::generic_err(format!("Invalid version {}", version)))
+        })
+        .collect::<StdResult<_>>()?;
+    Ok((numbers, pre.is_none(), pre.unwrap_or_default().to_string()))
+}
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn execute(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
     info: MessageInfo,
     msg: ExecuteMsg,
 ) -> Result<Response, ContractError> {
     match msg {
         ExecuteMsg::OpenOrder(msg) => {
-            execute_open_order(deps, Balance::from(info.funds), &info.sender, msg)
+            execute_open_order(deps, env, Balance::from(info.funds), &info.sender, msg)
+        }
+        ExecuteMsg::CloseOrder(msg) => {
+            execute_close_order(deps, env, Balance::from(info.funds), &info.sender, msg)
+        }
+        ExecuteMsg::CloseOrders { order_ids } => {
+            execute_close_orders(deps, env, info.funds, &info.sender, order_ids)
+        }
+        ExecuteMsg::CancelOrder { order_id } => execute_cancel_order(deps, &info.sender, order_id),
+        ExecuteMsg::CancelGroup { group_id } => execute_cancel_group(deps, &info.sender, group_id),
+        ExecuteMsg::InvalidateUnbacked { order_id } => {
+            execute_invalidate_unbacked(deps, env, &info.sender, order_id)
+        }
+        ExecuteMsg::DeclineOrder { order_id } => {
+            execute_decline_order(deps, &info.sender, order_id)
+        }
+        ExecuteMsg::MakePublic { order_id } => execute_make_public(deps, &info.sender, order_id),
+        ExecuteMsg::SplitOrder {
+            order_id,
+            first_fraction_bps,
+        } => execute_split_order(deps, env, &info.sender, order_id, first_fraction_bps),
+        ExecuteMsg::UpdateOrders { updates } => execute_update_orders(deps, &info.sender, updates),
+        ExecuteMsg::WithdrawFraction { order_id, bps } => {
+            execute_withdraw_fraction(deps, &info.sender, order_id, bps)
+        }
+        ExecuteMsg::TransferOrder {
+            order_id,
+            new_maker,
+        } => execute_transfer_order(deps, &info.sender, order_id, new_maker),
+        ExecuteMsg::ForceCloseBroken { order_id } => {
+            execute_force_close_broken(deps, &info.sender, order_id)
+        }
+        ExecuteMsg::ClaimStranded { cw20_address } => {
+            execute_claim_stranded(deps, &info.sender, cw20_address)
+        }
+        ExecuteMsg::ReclaimDeposit { order_id } => {
+            execute_reclaim_deposit(deps, &info.sender, order_id)
+        }
+        ExecuteMsg::UpdateConfig {
+            owner,
+            open_cooldown_blocks,
+            clear_open_cooldown,
+            denom_aliases,
+            asset_decimals,
+            blacklist,
+            oracle,
+            clear_oracle,
+            min_prices,
+        } => execute_update_config(
+            deps,
+            &info.sender,
+            owner,
+            open_cooldown_blocks,
+            clear_open_cooldown,
+            denom_aliases,
+            asset_decimals,
+            blacklist,
+            oracle,
+            clear_oracle,
+            min_prices,
+        ),
+        ExecuteMsg::RemapCw20 {
+            old_contract,
+            new_contract,
+        } => execute_remap_cw20(deps, &info.sender, old_contract, new_contract),
+        ExecuteMsg::BackfillOrders { limit } => execute_backfill_orders(deps, limit),
+        ExecuteMsg::Receive(msg) => execute_receive(deps, env, info, msg),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn execute_update_config(
+    deps: DepsMut,
+    sender: &Addr,
+    owner: Option<String>,
+    open_cooldown_blocks: Option<u64>,
+    clear_open_cooldown: bool,
+    denom_aliases: Option<Vec<(AssetRef, AssetRef)>>,
+    asset_decimals: Option<Vec<(String, u8)>>,
+    blacklist: Option<Vec<String>>,
+    oracle: Option<String>,
+    clear_oracle: bool,
+    min_prices: Option<Vec<(String, String, Decimal)>>,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    if *sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    if (clear_open_cooldown && open_cooldown_blocks.is_some()) || (clear_oracle && oracle.is_some())
+    {
+        return Err(ContractError::ConflictingConfigUpdate {});
+    }
+
+    if let Some(owner) = owner {
+        config.owner = deps.api.addr_validate(&owner)?;
+    }
+    if let Some(open_cooldown_blocks) = open_cooldown_blocks {
+        config.open_cooldown_blocks = Some(open_cooldown_blocks);
+    }
+    if clear_open_cooldown {
+        config.open_cooldown_blocks = None;
+    }
+    if let Some(denom_aliases) = denom_aliases {
+        config.denom_aliases = validate_aliases(deps.api, denom_aliases)?;
+    }
+    if let Some(asset_decimals) = asset_decimals {
+        config.asset_decimals = asset_decimals;
+    }
+    if let Some(blacklist) = blacklist {
+        config.blacklist = validate_addrs(deps.api, &blacklist)?;
+    }
+    if let Some(oracle) = oracle {
+        config.oracle = Some(deps.api.addr_validate(&oracle)?);
+    }
+    if clear_oracle {
+        config.oracle = None;
+    }
+    if let Some(min_prices) = min_prices {
+        config.min_prices = min_prices;
+    }
+    CONFIG.save(deps.storage, &config)?;
+
+    Ok(Response::new().add_attribute("method", "update_config"))
+}
+
+pub fn execute_remap_cw20(
+    deps: DepsMut,
+    sender: &Addr,
+    old_contract: String,
+    new_contract: String,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if *sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    let old_contract = deps.api.addr_validate(&old_contract)?;
+    let new_contract = deps.api.addr_validate(&new_contract)?;
+    if old_contract == new_contract {
+        return Err(ContractError::SameCw20Contract {});
+    }
+
+    let stored: Vec<(Vec<u8>, Order)> = orders()
+        .range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
+        .collect::<StdResult<_>>()?;
+    let mut remapped = 0u64;
+    let mut deposits_remapped = 0u64;
+    for (key, mut order) in stored {
+        let tokens = order
+            .maker_token
+            .cw20
+            .iter_mut()
+            .chain(order.taker_token.cw20.iter_mut())
+            .chain(order.remainder_from_allowance.iter_mut())
+            .filter(|token| token.address == old_contract);
+        let mut changed = false;
+        for token in tokens {
+            token.address = new_contract.clone();
+            changed = true;
+        }
+        if !changed {
+            continue;
         }
-        ExecuteMsg::CloseOrder { order_id } => {
-            execute_close_order(deps, Balance::from(info.funds), &info.sender, order_id)
+        let order_id = parse_order_id(&key)?;
+        orders().save(deps.storage, order_id.into(), &order)?;
+        remapped += 1;
+
+        // Cw20 stranded by a force close must be claimed from the new contract
+        let maker = &order.maker_address;
+        if let Some(stranded) = STRANDED.may_load(deps.storage, (maker, &old_contract))? {
+            STRANDED.remove(deps.storage, (maker, &old_contract));
+            STRANDED.update(
+                deps.storage,
+                (maker, &new_contract),
+                |existing| -> StdResult<_> {
+                    Ok(existing.unwrap_or_default().checked_add(stranded)?)
+                },
+            )?;
+        }
+
+        // Deposits left under the old contract would never count toward the order again
+        let deposits: Vec<PendingDeposit> = PENDING_DEPOSITS
+            .sub_prefix(order_id.into())
+            .range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
+            .map(|item| item.map(|(_, deposit)| deposit))
+            .collect::<StdResult<_>>()?;
+        for mut deposit in deposits {
+            if deposit.token.address != old_contract {
+                continue;
+            }
+            PENDING_DEPOSITS.remove(
+                deps.storage,
+                (order_id.into(), &deposit.taker_address, &old_contract),
+            );
+            let new_key = (order_id.into(), &deposit.taker_address, &new_contract);
+            if let Some(pending) = PENDING_DEPOSITS.may_load(deps.storage, new_key.clone())? {
+                deposit.token.amount += pending.token.amount;
+            }
+            deposit.token.address = new_contract.clone();
+            PENDING_DEPOSITS.save(deps.storage, new_key, &deposit)?;
+            deposits_remapped += 1;
         }
-        ExecuteMsg::Receive(msg) => execute_receive(deps, info, msg),
     }
+
+    Ok(Response::new()
+        .add_attribute("method", "remap_cw20")
+        .add_attribute("orders_remapped", remapped.to_string())
+        .add_attribute("deposits_remapped", deposits_remapped.to_string()))
+}
+
+pub fn execute_force_close_broken(
+    deps: DepsMut,
+    sender: &Addr,
+    order_id: u64,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if *sender != config.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    let mut order = orders().load(deps.storage, order_id.into())?;
+    if !order.is_open {
+        return Err(ContractError::OrderClosed {});
+    }
+    if order.maker_token.cw20.is_empty() {
+        return Err(ContractError::NoCw20Maker {});
+    }
+
+    order.is_open = false;
+    orders().save(deps.storage, order_id.into(), &order)?;
+
+    // Only the native part is returned; the broken cw20 is kept for the maker to claim
+    let refund = GenericBalance {
+        native: order.maker_token.native.clone(),
+        cw20: vec![],
+    };
+    let mut res = Response::new()
+        .add_attribute("method", "force_close_broken")
+        .add_attribute("order_id", order_id.to_string());
+    for token in &order.maker_token.cw20 {
+        STRANDED.update(
+            deps.storage,
+            (&order.maker_address, &token.address),
+            |stranded| -> StdResult<_> { Ok(stranded.unwrap_or_default() + token.amount) },
+        )?;
+        res = res.add_attribute("stranded", format!("{}:{}", token.address, token.amount));
+    }
+    let maker_messages = send_tokens(&order.maker_address, &refund)?;
+    let refund_messages = refund_pending_deposits(deps, order_id)?;
+    Ok(res
+        .add_submessages(maker_messages)
+        .add_submessages(refund_messages))
+}
+
+pub fn execute_claim_stranded(
+    deps: DepsMut,
+    maker_address: &Addr,
+    cw20_address: String,
+) -> Result<Response, ContractError> {
+    let cw20_address = deps.api.addr_validate(&cw20_address)?;
+    let amount = STRANDED
+        .may_load(deps.storage, (maker_address, &cw20_address))?
+        .ok_or(ContractError::NothingStranded {})?;
+    STRANDED.remove(deps.storage, (maker_address, &cw20_address));
+
+    let balance = GenericBalance {
+        native: vec![],
+        cw20: vec![Cw20CoinVerified {
+            address: cw20_address,
+            amount,
+        }],
+    };
+    Ok(Response::new()
+        .add_attribute("method", "claim_stranded")
+        .add_submessages(send_tokens(maker_address, &balance)?))
+}
+
+fn validate_aliases(
+    api: &dyn Api,
+    aliases: Vec<(AssetRef, AssetRef)>,
+) -> StdResult<Vec<(AssetRef, AssetRef)>> {
+    let validate = |asset: AssetRef| -> StdResult<AssetRef> {
+        match asset {
+            AssetRef::Native(denom) => Ok(AssetRef::Native(denom)),
+            AssetRef::Cw20(address) => Ok(AssetRef::Cw20(api.addr_validate(address.as_str())?)),
+        }
+    };
+    aliases
+        .into_iter()
+        .map(|(canonical, alias)| Ok((validate(canonical)?, validate(alias)?)))
+        .collect()
 }
 
 pub fn execute_receive(
     deps: DepsMut,
+    env: Env,
     info: MessageInfo,
     wrapper: Cw20ReceiveMsg,
 ) -> Result<Response, ContractError> {
     let msg: ReceiveMsg = from_binary(&wrapper.msg)?;
-    let balance = Balance::Cw20(Cw20CoinVerified {
+    // Anyone can call Receive directly, so make sure the sender is an actual cw20
+    // contract before treating the message as a token transfer from it
+    let token_info: StdResult<TokenInfoResponse> = deps
+        .querier
+        .query_wasm_smart(&info.sender, &Cw20QueryMsg::TokenInfo {});
+    if token_info.is_err() {
+        return Err(ContractError::InvalidCw20 {});
+    }
+    let token = Cw20CoinVerified {
         address: info.sender,
         amount: wrapper.amount,
-    });
+    };
     let api = deps.api;
     match msg {
-        ReceiveMsg::OpenOrder(msg) => {
-            execute_open_order(deps, balance, &api.addr_validate(&wrapper.sender)?, msg)
-        }
-        ReceiveMsg::CloseOrder { order_id } => execute_close_order(
+        ReceiveMsg::OpenOrder(msg) => execute_open_order(
             deps,
-            balance,
+            env,
+            Balance::Cw20(token),
             &api.addr_validate(&wrapper.sender)?,
-            order_id,
+            msg,
         ),
+        ReceiveMsg::CloseOrder(msg) => {
+            execute_deposit(deps, env, token, &api.addr_validate(&wrapper.sender)?, msg)
+        }
+    }
+}
+
+/// Accumulates cw20 deposits toward an order demanding that token, and closes the
+/// order once the deposits add up to the demanded amount.
+pub fn execute_deposit(
+    deps: DepsMut,
+    env: Env,
+    mut token: Cw20CoinVerified,
+    taker_address: &Addr,
+    message: CloseOrderMsg,
+) -> Result<Response, ContractError> {
+    let order_id = message.order_id;
+    let order = orders().load(deps.storage, order_id.into())?;
+    // Deposits toward an expired order could only ever be reclaimed
+    if order.is_open && order.is_expired(env.block.height) {
+        return Err(ContractError::OrderExpired {});
+    }
+    let config = CONFIG.load(deps.storage)?;
+    // Reject tokens from a cw20 other than the demanded one, or an alias of it, before
+    // any other check of the close
+    if let [demanded] = order.taker_token.cw20.as_slice() {
+        let aliases = &config.denom_aliases;
+        if order.is_open
+            && AssetRef::Cw20(token.address.clone()).canonical(aliases)
+                != AssetRef::Cw20(demanded.address.clone()).canonical(aliases)
+        {
+            return Err(ContractError::OrderUnmatched {});
+        }
+    }
+    let demanded = match order.taker_token.cw20.as_slice() {
+        [demanded]
+            if order.is_open
+                && order.price_in_usd.is_none()
+                && demanded.address == token.address =>
+        {
+            demanded
+        }
+        // Anything else cannot be accumulated, let the close reject or settle it
+        _ => return execute_close_order(deps, env, Balance::Cw20(token), taker_address, message),
+    };
+    check_taker(&config, &order, taker_address, &message.ack)?;
+
+    let token_address = token.address.clone();
+    let key = (order_id.into(), taker_address, &token_address);
+    if let Some(pending) = PENDING_DEPOSITS.may_load(deps.storage, key.clone())? {
+        token.amount += pending.token.amount;
+    }
+
+    if token.amount < demanded.amount {
+        let deposited = token.amount;
+        PENDING_DEPOSITS.save(
+            deps.storage,
+            key,
+            &PendingDeposit {
+                taker_address: taker_address.clone(),
+                token,
+            },
+        )?;
+        return Ok(Response::new()
+            .add_attribute("method", "deposit")
+            .add_attribute("order_id", order_id.to_string())
+            .add_attribute("deposited", deposited.to_string()));
+    }
+
+    PENDING_DEPOSITS.remove(deps.storage, key);
+    execute_close_order(deps, env, Balance::Cw20(token), taker_address, message)
+}
+
+pub fn execute_reclaim_deposit(
+    deps: DepsMut,
+    taker_address: &Addr,
+    order_id: u64,
+) -> Result<Response, ContractError> {
+    let deposits: Vec<PendingDeposit> = PENDING_DEPOSITS
+        .prefix((order_id.into(), taker_address))
+        .range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
+        .map(|item| item.map(|(_, deposit)| deposit))
+        .collect::<StdResult<_>>()?;
+    if deposits.is_empty() {
+        return Err(ContractError::NoPendingDeposit {});
+    }
+
+    let mut refund = GenericBalance::default();
+    for deposit in deposits {
+        PENDING_DEPOSITS.remove(
+            deps.storage,
+            (order_id.into(), taker_address, &deposit.token.address),
+        );
+        refund.add_tokens(Balance::Cw20(deposit.token));
     }
+
+    Ok(Response::new()
+        .add_attribute("method", "reclaim_deposit")
+        .add_attribute("order_id", order_id.to_string())
+        .add_submessages(send_tokens(taker_address, &refund)?))
 }
 
 pub fn execute_open_order(
     deps: DepsMut,
+    env: Env,
     balance: Balance,
     sender: &Addr,
     message: OpenOrderMsg,
@@ -79,111 +593,992 @@ pub fn execute_open_order(
         return Err(ContractError::EmptyBalance {});
     }
 
-    if message.taker_token.native.is_empty() && message.taker_token.cw20.is_empty() {
-        return Err(ContractError::OrderInvalid(String::from(
-            "At least one native/cw20 token should be specified as a taker.",
-        )));
-    } else if message.taker_token.native.is_empty() && message.taker_token.cw20.len() > 1 {
-        return Err(ContractError::OrderInvalid(String::from(
-            "Only one cw20 token can be specified as a taker.",
-        )));
-    } else if !message.taker_token.native.is_empty() && !message.taker_token.cw20.is_empty() {
-        return Err(ContractError::OrderInvalid(String::from(
-            "Only one native or cw20 token can be specified as a taker.",
-        )));
+    let config = CONFIG.load(deps.storage)?;
+    let maker_order_balance = match balance {
+        Balance::Native(balance) => GenericBalance {
+            native: balance.0,
+            cw20: vec![],
+        },
+        Balance::Cw20(token) => GenericBalance {
+            native: vec![],
+            cw20: vec![token],
+        },
+    };
+    let order = build_order(
+        deps.as_ref(),
+        &env,
+        &config,
+        sender,
+        maker_order_balance,
+        message,
+    )?;
+    if config.open_cooldown_blocks.is_some() {
+        LAST_OPEN.save(deps.storage, sender, &env.block.height)?;
     }
 
-    let maker_order_balance = match balance {
-        Balance::Native(balance) => {
-            if !message.taker_token.native.is_empty() {
-                return Err(ContractError::OrderInvalid(String::from(
-                    "Maker and taker tokens cannot both be native tokens.",
-                )));
-            }
-            GenericBalance {
-                native: balance.0,
-                cw20: vec![],
+    let id = save_new_order(deps.storage, &env, &order)?;
+
+    // Emitted as `wasm-order_opened` so subscribers can filter on order_id
+    let event = Event::new("order_opened").add_attribute("order_id", id.to_string());
+
+    Ok(Response::new()
+        .add_attribute("method", "open_order")
+        .add_attribute("order_id", id.to_string())
+        .add_attribute("maker_address", sender)
+        .add_event(event))
+}
+
+/// Validates an open of `maker_order_balance` by the maker and builds the order it would
+/// store. Shared by the open and its simulation, so they cannot disagree.
+fn build_order(
+    deps: Deps,
+    env: &Env,
+    config: &Config,
+    sender: &Addr,
+    maker_order_balance: GenericBalance,
+    message: OpenOrderMsg,
+) -> Result<Order, ContractError> {
+    if let Some(cooldown) = config.open_cooldown_blocks {
+        if let Some(last_open) = LAST_OPEN.may_load(deps.storage, sender)? {
+            if env.block.height < last_open.saturating_add(cooldown) {
+                return Err(ContractError::Cooldown {});
             }
         }
-        Balance::Cw20(token) => {
-            if !message.taker_token.cw20.is_empty()
-                && message.taker_token.cw20[0].address == token.address
-            {
-                return Err(ContractError::OrderInvalid(String::from(
-                    "Maker and taker tokens cannot be the same cw20 tokens.",
-                )));
-            }
-            GenericBalance {
-                native: vec![],
-                cw20: vec![token],
+    }
+
+    // Coins of other denoms may come with a zero amount, which would be dust in the order
+    if maker_order_balance.has_zero_amount() {
+        return Err(ContractError::OrderInvalidReason(
+            OrderInvalidReason::ZeroMakerAmount,
+        ));
+    }
+
+    // A demand listing a denom twice asks for the sum of both amounts
+    let taker_token = message.taker_token.merged();
+    if message.is_gift {
+        let taker_is_empty = taker_token.native.is_empty() && taker_token.cw20.is_empty();
+        if !taker_is_empty || message.target_address.is_none() {
+            return Err(ContractError::OrderInvalidReason(
+                OrderInvalidReason::InvalidGift,
+            ));
+        }
+    } else {
+        check_taker_token(&maker_order_balance, &taker_token)?;
+    }
+
+    if matches!(message.expires_at_height, Some(height) if env.block.height >= height) {
+        return Err(ContractError::OrderInvalidReason(
+            OrderInvalidReason::ExpiryPassed,
+        ));
+    }
+
+    if message.price_in_usd.is_some() && taker_token.single_asset().is_none() {
+        return Err(ContractError::OrderInvalidReason(
+            OrderInvalidReason::UsdPricedTaker,
+        ));
+    }
+
+    let remainder_from_allowance = match message.remainder_from_allowance {
+        Some(remainder) if remainder.amount.is_zero() => {
+            return Err(ContractError::OrderInvalidReason(
+                OrderInvalidReason::ZeroAllowanceRemainder,
+            ));
+        }
+        Some(remainder) => {
+            let remainder = Cw20CoinVerified {
+                address: deps.api.addr_validate(&remainder.address)?,
+                amount: remainder.amount,
+            };
+            // Otherwise a token bond could list any size in the book
+            if !remainder_backed(deps, env, sender, &remainder)? {
+                return Err(ContractError::OrderInvalidReason(
+                    OrderInvalidReason::UnbackedRemainder,
+                ));
             }
+            Some(remainder)
         }
+        None => None,
     };
 
+    if let Some(expected) = &message.expected_maker_token {
+        if !expected.matches(&maker_order_balance) {
+            return Err(ContractError::OrderInvalidReason(
+                OrderInvalidReason::MakerTokenMismatch,
+            ));
+        }
+    }
+
+    // Store the normalized form of the target so the close check is an exact comparison
+    let target_address = message
+        .target_address
+        .map(|target| -> StdResult<Addr> {
+            deps.api
+                .addr_humanize(&deps.api.addr_canonicalize(&target)?)
+        })
+        .transpose()?;
+    let maker_callback = message
+        .maker_callback
+        .map(|addr| deps.api.addr_validate(&addr))
+        .transpose()?;
+    let deposit_to_vault = message
+        .deposit_to_vault
+        .map(|addr| deps.api.addr_validate(&addr))
+        .transpose()?;
+
     let order = Order {
         maker_address: sender.clone(),
         maker_token: maker_order_balance,
-        taker_token: message.taker_token,
-        target_address: message.target_address,
+        taker_token,
+        target_address,
         is_open: true,
+        memo: message.memo,
+        require_taker_ack: message.require_taker_ack,
+        maker_callback,
+        refund_overpayment: message.refund_overpayment,
+        price_in_usd: message.price_in_usd,
+        remainder_from_allowance,
+        deposit_to_vault,
+        // An empty group id is treated as no group, which is not indexed
+        group_id: message.group_id.filter(|group_id| !group_id.is_empty()),
+        expires_at_height: message.expires_at_height,
     };
 
-    let id = next_id(deps.storage)?;
-    ORDERS.save(deps.storage, id.into(), &order)?;
+    check_min_price(config, &order)?;
+    Ok(order)
+}
 
-    Ok(Response::new()
-        .add_attribute("method", "open_order")
-        .add_attribute("order_id", id.to_string()))
+/// Tells whether the maker's balance and allowance to this contract both cover the
+/// remainder pulled at close
+fn remainder_backed(
+    deps: Deps,
+    env: &Env,
+    maker: &Addr,
+    remainder: &Cw20CoinVerified,
+) -> StdResult<bool> {
+    let balance: BalanceResponse = deps.querier.query_wasm_smart(
+        &remainder.address,
+        &Cw20QueryMsg::Balance {
+            address: maker.to_string(),
+        },
+    )?;
+    let allowance: AllowanceResponse = deps.querier.query_wasm_smart(
+        &remainder.address,
+        &Cw20QueryMsg::Allowance {
+            owner: maker.to_string(),
+            spender: env.contract.address.to_string(),
+        },
+    )?;
+    Ok(balance.balance >= remainder.amount
+        && allowance.allowance >= remainder.amount
+        && !allowance.expires.is_expired(&env.block))
+}
+
+/// Stores an order under the next id along with where it was created
+fn save_new_order(storage: &mut dyn Storage, env: &Env, order: &Order) -> StdResult<u64> {
+    let id = next_id(storage)?;
+    orders().save(storage, id.into(), order)?;
+    let meta = OrderMeta {
+        block_height: env.block.height,
+        tx_index: env.transaction.as_ref().map(|t| t.index),
+    };
+    ORDER_META.save(storage, id.into(), &meta)?;
+    Ok(id)
+}
+
+/// Checks the demanded tokens of a regular (non-gift) order against its maker tokens
+fn check_taker_token(
+    maker_token: &GenericBalance,
+    taker_token: &GenericBalance,
+) -> Result<(), ContractError> {
+    if taker_token.native.is_empty() && taker_token.cw20.is_empty() {
+        return Err(ContractError::OrderInvalidReason(
+            OrderInvalidReason::EmptyTaker,
+        ));
+    } else if taker_token.has_zero_amount() {
+        return Err(ContractError::OrderInvalidReason(
+            OrderInvalidReason::ZeroTakerAmount,
+        ));
+    } else if taker_token.cw20.len() > 1 {
+        return Err(ContractError::OrderInvalidReason(
+            OrderInvalidReason::MultipleCw20Taker,
+        ));
+    } else if !taker_token.native.is_empty() && !taker_token.cw20.is_empty() {
+        return Err(ContractError::OrderInvalidReason(
+            OrderInvalidReason::MixedTaker,
+        ));
+    }
+
+    if !maker_token.native.is_empty() && !taker_token.native.is_empty() {
+        return Err(ContractError::OrderInvalidReason(
+            OrderInvalidReason::BothNative,
+        ));
+    }
+    let same_token = maker_token.cw20.iter().any(|maker| {
+        taker_token
+            .cw20
+            .iter()
+            .any(|taker| taker.address == maker.address)
+    });
+    if same_token {
+        return Err(ContractError::OrderInvalidReason(
+            OrderInvalidReason::MakerTakerSameToken,
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects single-asset orders priced below the configured floor of their pair. USD-priced
+/// orders only get a taker amount at close, so they have no price to check.
+fn check_min_price(config: &Config, order: &Order) -> Result<(), ContractError> {
+    if order.price_in_usd.is_some() {
+        return Ok(());
+    }
+    check_pair_min_price(config, &order.offered_token(), &order.taker_token)
+}
+
+fn check_pair_min_price(
+    config: &Config,
+    maker_token: &GenericBalance,
+    taker_token: &GenericBalance,
+) -> Result<(), ContractError> {
+    let (maker_denom, taker_denom) = match (maker_token.single_asset(), taker_token.single_asset())
+    {
+        (Some((maker_denom, _)), Some((taker_denom, _))) => (maker_denom, taker_denom),
+        _ => return Ok(()),
+    };
+    let floor = config
+        .min_prices
+        .iter()
+        .find(|(maker, taker, _)| maker == maker_denom && taker == taker_denom);
+    // Only pairs with a floor need a price
+    if let Some((_, _, min_price)) = floor {
+        let price = implied_price(maker_token, taker_token, &config.asset_decimals);
+        if matches!(price, Some(price) if price < *min_price) {
+            return Err(ContractError::OrderInvalidReason(
+                OrderInvalidReason::BelowMinPrice,
+            ));
+        }
+    }
+    Ok(())
 }
 
 pub fn execute_close_order(
     deps: DepsMut,
+    env: Env,
     balance: Balance,
     taker_address: &Addr,
-    order_id: u64,
+    message: CloseOrderMsg,
 ) -> Result<Response, ContractError> {
+    let order_id = message.order_id;
     // find the Order from the id
-    let mut order = ORDERS.load(deps.storage, order_id.into())?;
+    let mut order = orders().load(deps.storage, order_id.into())?;
+    // When called via Receive, returning an error reverts the cw20 transfer that
+    // triggered it as well, so the taker's tokens are never stuck in the contract
     if !order.is_open {
         return Err(ContractError::OrderClosed {});
     }
-
-    // Reject if target address exists and is not equal to the order taker address
-    if let Some(target_address) = &order.target_address {
-        if *taker_address != deps.api.addr_validate(target_address.as_str())? {
-            return Err(ContractError::OrderReserved {});
+    if order.is_expired(env.block.height) {
+        return Err(ContractError::OrderExpired {});
+    }
+    if let Some(expected_hash) = &message.expected_hash {
+        if *expected_hash != order.hash()? {
+            return Err(ContractError::StaleOrder {});
         }
-    };
+    }
+    let config = CONFIG.load(deps.storage)?;
+    check_taker(&config, &order, taker_address, &message.ack)?;
+    if let Some(price_in_usd) = order.price_in_usd {
+        // Record the resolved demand, so the closed order shows what was actually paid
+        order.taker_token = resolve_usd_demand(deps.as_ref(), &config, &order, price_in_usd)?;
+    }
+    if let Some(max_price) = message.max_price {
+        match order.implied_price(&config.asset_decimals) {
+            Some(price) if price <= max_price => {}
+            _ => return Err(ContractError::SlippageExceeded {}),
+        }
+    }
+    let taker_callback = message
+        .taker_callback
+        .map(|contract| deps.api.addr_validate(&contract))
+        .transpose()?;
 
-    let taker_order_balance = match balance {
-        Balance::Native(balance) => GenericBalance {
-            native: balance.0,
-            cw20: vec![],
-        },
-        Balance::Cw20(token) => GenericBalance {
-            native: vec![],
-            cw20: vec![token],
-        },
-    };
+    // Coins of the same denom sent separately count toward a single demand
+    let mut paid_balance = GenericBalance::default();
+    paid_balance.add_tokens(balance);
 
-    if taker_order_balance != order.taker_token {
+    // Aliased assets are interchangeable, so compare their canonical forms
+    let aliases = &config.denom_aliases;
+    let (taker_order_balance, surplus) = if paid_balance
+        .canonicalize(aliases)
+        .matches(&order.taker_token.canonicalize(aliases))
+    {
+        (paid_balance, GenericBalance::default())
+    } else if order.refund_overpayment || paid_balance.cw20.is_empty() {
+        // The surplus is only worked out against the demanded form of each asset
+        let surplus = paid_balance
+            .checked_sub(&order.taker_token)
+            .ok_or(ContractError::OrderUnmatched {})?;
+        (order.taker_token.clone(), surplus)
+    } else {
         return Err(ContractError::OrderUnmatched {});
-    }
+    };
 
     order.is_open = false;
-    ORDERS.save(deps.storage, order_id.into(), &order)?;
+    orders().save(deps.storage, order_id.into(), &order)?;
+    record_price(deps.storage, &order, env.block.time.seconds())?;
+    record_volume(deps.storage, &taker_order_balance)?;
+    record_volume(deps.storage, &order.offered_token())?;
 
-    let maker_messages = send_tokens(&order.maker_address, &taker_order_balance)?;
+    let maker_messages = match &order.deposit_to_vault {
+        Some(vault) => deposit_tokens(vault, &order.maker_address, &taker_order_balance)?,
+        None => send_tokens(&order.maker_address, &taker_order_balance)?,
+    };
     let taker_messages = send_tokens(taker_address, &order.maker_token)?;
+    let remainder_messages = match &order.remainder_from_allowance {
+        Some(remainder) => {
+            let msg = Cw20ExecuteMsg::TransferFrom {
+                owner: order.maker_address.to_string(),
+                recipient: taker_address.to_string(),
+                amount: remainder.amount,
+            };
+            vec![SubMsg::new(WasmMsg::Execute {
+                contract_addr: remainder.address.to_string(),
+                msg: to_binary(&msg)?,
+                funds: vec![],
+            })]
+        }
+        None => vec![],
+    };
+    let surplus_messages = send_tokens(taker_address, &surplus)?;
+    let refund_messages = refund_pending_deposits(deps, order_id)?;
+    let callback_messages = match &order.maker_callback {
+        Some(contract) => {
+            let msg = MakerCallbackMsg::OrderFilled {
+                order_id,
+                taker_address: taker_address.clone(),
+                taker_token: taker_order_balance,
+            };
+            // Settlement must go through even if the maker's hook fails
+            vec![SubMsg::reply_on_error(
+                WasmMsg::Execute {
+                    contract_addr: contract.to_string(),
+                    msg: to_binary(&msg)?,
+                    funds: vec![],
+                },
+                MAKER_CALLBACK_REPLY_ID,
+            )]
+        }
+        None => vec![],
+    };
+    let taker_callback_messages = match taker_callback {
+        Some(contract) => {
+            let msg = TakerCallbackMsg::Filled {
+                order_id,
+                maker_token: order.maker_token.clone(),
+            };
+            vec![SubMsg::new(WasmMsg::Execute {
+                contract_addr: contract.to_string(),
+                msg: to_binary(&msg)?,
+                funds: vec![],
+            })]
+        }
+        None => vec![],
+    };
+
+    let mut details = vec![
+        attr("maker_address", &order.maker_address),
+        attr("taker_address", taker_address),
+    ];
+    if let Some(target) = &order.target_address {
+        details.push(attr("target_address", target));
+    }
+    if let Some(price) = order.implied_price(&config.asset_decimals) {
+        details.push(attr("exec_price", price.to_string()));
+    }
+
+    // Emitted as `wasm-order_closed` so subscribers can filter on order_id. The event
+    // carries the close details too, as batch closes only forward events.
+    let event = Event::new("order_closed")
+        .add_attribute("order_id", order_id.to_string())
+        .add_attributes(details.clone());
 
     Ok(Response::new()
         .add_attribute("method", "close_order")
         .add_attribute("order_id", order_id.to_string())
+        .add_attributes(details)
+        .add_event(event)
         .add_submessages(maker_messages)
-        .add_submessages(taker_messages))
+        .add_submessages(taker_messages)
+        .add_submessages(remainder_messages)
+        .add_submessages(surplus_messages)
+        .add_submessages(refund_messages)
+        .add_submessages(callback_messages)
+        .add_submessages(taker_callback_messages))
 }
 
-fn send_tokens(to: &Addr, balance: &GenericBalance) -> StdResult<Vec<SubMsg>> {
+pub fn execute_cancel_order(
+    deps: DepsMut,
+    sender: &Addr,
+    order_id: u64,
+) -> Result<Response, ContractError> {
+    let mut order = orders().load(deps.storage, order_id.into())?;
+    if *sender != order.maker_address {
+        return Err(ContractError::Unauthorized {});
+    }
+    if !order.is_open {
+        return Err(ContractError::OrderClosed {});
+    }
+
+    order.is_open = false;
+    orders().save(deps.storage, order_id.into(), &order)?;
+
+    let maker_messages = send_tokens(&order.maker_address, &order.maker_token)?;
+    let refund_messages = refund_pending_deposits(deps, order_id)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "cancel_order")
+        .add_attribute("order_id", order_id.to_string())
+        .add_submessages(maker_messages)
+        .add_submessages(refund_messages))
+}
+
+pub fn execute_cancel_group(
+    mut deps: DepsMut,
+    sender: &Addr,
+    group_id: String,
+) -> Result<Response, ContractError> {
+    // The group is looked up under the sender, so others cannot bloat it
+    let order_ids: Vec<u64> = orders()
+        .idx
+        .group
+        .order_ids(deps.storage, sender, &group_id, None)
+        .collect::<StdResult<_>>()?;
+    let mut response = Response::new().add_attribute("method", "cancel_group");
+    for order_id in order_ids {
+        let mut order = orders().load(deps.storage, order_id.into())?;
+        if !order.is_open {
+            continue;
+        }
+        order.is_open = false;
+        orders().save(deps.storage, order_id.into(), &order)?;
+        response = response
+            .add_attribute("order_id", order_id.to_string())
+            .add_submessages(send_tokens(&order.maker_address, &order.maker_token)?)
+            .add_submessages(refund_pending_deposits(deps.branch(), order_id)?);
+    }
+    Ok(response)
+}
+
+pub fn execute_invalidate_unbacked(
+    deps: DepsMut,
+    env: Env,
+    sender: &Addr,
+    order_id: u64,
+) -> Result<Response, ContractError> {
+    let mut order = orders().load(deps.storage, order_id.into())?;
+    if !order.is_open {
+        return Err(ContractError::OrderClosed {});
+    }
+    let remainder = match &order.remainder_from_allowance {
+        Some(remainder) => remainder,
+        // Fully escrowed orders are always backed
+        None => return Err(ContractError::OrderBacked {}),
+    };
+    if remainder_backed(deps.as_ref(), &env, &order.maker_address, remainder)? {
+        return Err(ContractError::OrderBacked {});
+    }
+
+    order.is_open = false;
+    orders().save(deps.storage, order_id.into(), &order)?;
+
+    // The maker broke the order's promise, so the bond goes to whoever caught it
+    let bond_messages = send_tokens(sender, &order.maker_token)?;
+    let refund_messages = refund_pending_deposits(deps, order_id)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "invalidate_unbacked")
+        .add_attribute("order_id", order_id.to_string())
+        .add_attribute("bond_recipient", sender)
+        .add_submessages(bond_messages)
+        .add_submessages(refund_messages))
+}
+
+pub fn execute_decline_order(
+    deps: DepsMut,
+    sender: &Addr,
+    order_id: u64,
+) -> Result<Response, ContractError> {
+    let mut order = orders().load(deps.storage, order_id.into())?;
+    match &order.target_address {
+        Some(target_address) if sender == target_address => {}
+        _ => return Err(ContractError::Unauthorized {}),
+    }
+    if !order.is_open {
+        return Err(ContractError::OrderClosed {});
+    }
+
+    order.is_open = false;
+    orders().save(deps.storage, order_id.into(), &order)?;
+
+    let maker_messages = send_tokens(&order.maker_address, &order.maker_token)?;
+    let refund_messages = refund_pending_deposits(deps, order_id)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "decline_order")
+        .add_attribute("order_id", order_id.to_string())
+        .add_submessages(maker_messages)
+        .add_submessages(refund_messages))
+}
+
+pub fn execute_make_public(
+    deps: DepsMut,
+    sender: &Addr,
+    order_id: u64,
+) -> Result<Response, ContractError> {
+    let mut order = orders().load(deps.storage, order_id.into())?;
+    if *sender != order.maker_address {
+        return Err(ContractError::Unauthorized {});
+    }
+    if !order.is_open {
+        return Err(ContractError::OrderClosed {});
+    }
+
+    order.target_address = None;
+    orders().save(deps.storage, order_id.into(), &order)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "make_public")
+        .add_attribute("order_id", order_id.to_string()))
+}
+
+pub fn execute_transfer_order(
+    deps: DepsMut,
+    sender: &Addr,
+    order_id: u64,
+    new_maker: String,
+) -> Result<Response, ContractError> {
+    let mut order = orders().load(deps.storage, order_id.into())?;
+    if *sender != order.maker_address {
+        return Err(ContractError::Unauthorized {});
+    }
+    if !order.is_open {
+        return Err(ContractError::OrderClosed {});
+    }
+
+    // The remainder is pulled from the maker's allowance at close, so moving the order
+    // would draw on the new maker's funds without their consent
+    if order.remainder_from_allowance.is_some() {
+        return Err(ContractError::NotTransferable {});
+    }
+
+    order.maker_address = deps.api.addr_validate(&new_maker)?;
+    order.maker_callback = None;
+    order.deposit_to_vault = None;
+    orders().save(deps.storage, order_id.into(), &order)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "transfer_order")
+        .add_attribute("order_id", order_id.to_string())
+        .add_attribute("new_maker", order.maker_address))
+}
+
+pub fn execute_split_order(
+    deps: DepsMut,
+    env: Env,
+    sender: &Addr,
+    order_id: u64,
+    first_fraction_bps: u16,
+) -> Result<Response, ContractError> {
+    let mut order = orders().load(deps.storage, order_id.into())?;
+    if *sender != order.maker_address {
+        return Err(ContractError::Unauthorized {});
+    }
+    if !order.is_open {
+        return Err(ContractError::OrderClosed {});
+    }
+
+    // Only the escrowed bond could be divided, which would change the price
+    if order.remainder_from_allowance.is_some() || first_fraction_bps > 10_000 {
+        return Err(ContractError::InvalidSplit {});
+    }
+
+    let (maker_first, maker_second) = order.maker_token.split(first_fraction_bps);
+    // The taker token of a USD-priced order is a placeholder, its USD demand is divided
+    // instead
+    let (taker_first, taker_second, price_first, price_second) = match order.price_in_usd {
+        Some(price) => {
+            let first = price.multiply_ratio(first_fraction_bps, 10_000u128);
+            if first.is_zero() || first == price {
+                return Err(ContractError::InvalidSplit {});
+            }
+            let taker = order.taker_token.clone();
+            (taker.clone(), taker, Some(first), Some(price - first))
+        }
+        None => {
+            let (first, second) = order.taker_token.split(first_fraction_bps);
+            (first, second, None, None)
+        }
+    };
+    if [&maker_first, &maker_second, &taker_first, &taker_second]
+        .iter()
+        .any(|balance| balance.has_zero_amount())
+    {
+        return Err(ContractError::InvalidSplit {});
+    }
+
+    // Escrow stays in the contract, it is only divided between the two orders
+    let new_order = Order {
+        maker_token: maker_second,
+        taker_token: taker_second,
+        price_in_usd: price_second,
+        ..order.clone()
+    };
+    order.maker_token = maker_first;
+    order.taker_token = taker_first;
+    order.price_in_usd = price_first;
+    orders().save(deps.storage, order_id.into(), &order)?;
+    let new_id = save_new_order(deps.storage, &env, &new_order)?;
+
+    // The split-off order is announced like any other opened order
+    let event = Event::new("order_opened").add_attribute("order_id", new_id.to_string());
+
+    Ok(Response::new()
+        .add_attribute("method", "split_order")
+        .add_attribute("order_id", order_id.to_string())
+        .add_attribute("new_order_id", new_id.to_string())
+        .add_event(event))
+}
+
+pub fn execute_update_orders(
+    deps: DepsMut,
+    sender: &Addr,
+    updates: Vec<(u64, GenericBalance)>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let mut response = Response::new().add_attribute("method", "update_orders");
+    // Any invalid entry fails the whole call, reverting the updates before it
+    for (order_id, taker_token) in updates {
+        let mut order = orders().load(deps.storage, order_id.into())?;
+        if *sender != order.maker_address {
+            return Err(ContractError::Unauthorized {});
+        }
+        if !order.is_open {
+            return Err(ContractError::OrderClosed {});
+        }
+        let taker_token = taker_token.merged();
+        check_taker_token(&order.maker_token, &taker_token)?;
+        if order.price_in_usd.is_some() && taker_token.single_asset().is_none() {
+            return Err(ContractError::OrderInvalidReason(
+                OrderInvalidReason::UsdPricedTaker,
+            ));
+        }
+
+        order.taker_token = taker_token;
+        check_min_price(&config, &order)?;
+        orders().save(deps.storage, order_id.into(), &order)?;
+        response = response.add_attribute("order_id", order_id.to_string());
+    }
+    Ok(response)
+}
+
+pub fn execute_withdraw_fraction(
+    deps: DepsMut,
+    sender: &Addr,
+    order_id: u64,
+    bps: u16,
+) -> Result<Response, ContractError> {
+    let mut order = orders().load(deps.storage, order_id.into())?;
+    if *sender != order.maker_address {
+        return Err(ContractError::Unauthorized {});
+    }
+    if !order.is_open {
+        return Err(ContractError::OrderClosed {});
+    }
+    // The part pulled from the allowance at close would keep its full amount
+    if order.remainder_from_allowance.is_some() || bps > 10_000 {
+        return Err(ContractError::InvalidWithdrawal {});
+    }
+
+    let (withdrawn, maker_left) = order.maker_token.split(bps);
+    let (_, taker_left) = order.taker_token.split(bps);
+    if [&withdrawn, &maker_left, &taker_left]
+        .iter()
+        .any(|balance| balance.has_zero_amount())
+    {
+        return Err(ContractError::InvalidWithdrawal {});
+    }
+
+    order.maker_token = maker_left;
+    order.taker_token = taker_left;
+    order.price_in_usd = order
+        .price_in_usd
+        .map(|price| price - price.multiply_ratio(bps, 10_000u128));
+    orders().save(deps.storage, order_id.into(), &order)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "withdraw_fraction")
+        .add_attribute("order_id", order_id.to_string())
+        .add_submessages(send_tokens(&order.maker_address, &withdrawn)?))
+}
+
+/// Returns the deposits other takers accumulated toward an order that has been closed
+fn refund_pending_deposits(deps: DepsMut, order_id: u64) -> StdResult<Vec<SubMsg>> {
+    let deposits: Vec<PendingDeposit> = PENDING_DEPOSITS
+        .sub_prefix(order_id.into())
+        .range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
+        .map(|item| item.map(|(_, deposit)| deposit))
+        .collect::<StdResult<_>>()?;
+
+    let mut msgs = vec![];
+    for deposit in deposits {
+        PENDING_DEPOSITS.remove(
+            deps.storage,
+            (
+                order_id.into(),
+                &deposit.taker_address,
+                &deposit.token.address,
+            ),
+        );
+        let balance = GenericBalance {
+            native: vec![],
+            cw20: vec![deposit.token],
+        };
+        msgs.append(&mut send_tokens(&deposit.taker_address, &balance)?);
+    }
+    Ok(msgs)
+}
+
+/// Works out how much of the taker token is worth `price_in_usd`, rounding up
+fn resolve_usd_demand(
+    deps: Deps,
+    config: &Config,
+    order: &Order,
+    price_in_usd: Uint128,
+) -> Result<GenericBalance, ContractError> {
+    let oracle = config
+        .oracle
+        .as_ref()
+        .ok_or(ContractError::OracleUnavailable {})?;
+    let (denom, _) = order
+        .taker_token
+        .single_asset()
+        .ok_or(ContractError::OracleUnavailable {})?;
+    let response: StdResult<OraclePriceResponse> = deps.querier.query_wasm_smart(
+        oracle,
+        &OracleQueryMsg::Price {
+            denom: denom.to_string(),
+        },
+    );
+    let price = match response {
+        Ok(response) if !response.price.is_zero() => response.price,
+        _ => return Err(ContractError::OracleUnavailable {}),
+    };
+
+    // Uint128 cannot be divided by a Decimal, so divide by the price's 10^18 atomics,
+    // in whole and fractional parts so an extreme price cannot overflow
+    const FRACTIONAL: u128 = 1_000_000_000_000_000_000;
+    let atomics = (Uint128::new(FRACTIONAL) * price).u128();
+    let whole = price_in_usd.u128() / atomics;
+    let fraction = Uint128::new(price_in_usd.u128() % atomics).multiply_ratio(FRACTIONAL, atomics);
+    let mut amount = whole
+        .checked_mul(FRACTIONAL)
+        .and_then(|whole| whole.checked_add(fraction.u128()))
+        .map(Uint128::new)
+        .ok_or(ContractError::UsdDemandOutOfRange {})?;
+    if amount.multiply_ratio(atomics, FRACTIONAL) < price_in_usd {
+        amount = amount
+            .checked_add(Uint128::new(1))
+            .map_err(|_| ContractError::UsdDemandOutOfRange {})?;
+    }
+
+    let mut demand = order.taker_token.clone();
+    for coin in demand.native.iter_mut() {
+        coin.amount = amount;
+    }
+    for token in demand.cw20.iter_mut() {
+        token.amount = amount;
+    }
+    Ok(demand)
+}
+
+/// Appends the price of a closed single-asset order to its pair's TWAP history
+fn record_price(storage: &mut dyn Storage, order: &Order, now: u64) -> StdResult<()> {
+    let offered = order.offered_token();
+    let (base, maker_amount) = match offered.single_asset() {
+        Some(asset) if !asset.1.is_zero() => asset,
+        _ => return Ok(()),
+    };
+    let (quote, taker_amount) = match order.taker_token.single_asset() {
+        Some(asset) => asset,
+        None => return Ok(()),
+    };
+
+    let mut state = TWAP.may_load(storage, (base, quote))?.unwrap_or_default();
+    let cumulative = match state.observations.last() {
+        // Closes within the same block only replace the price going forward
+        Some(last) if last.time == now => state.observations.pop().unwrap().cumulative,
+        Some(last) => match last.cumulative_at(now) {
+            Ok(cumulative) => cumulative,
+            // A price feed must never revert a settlement, so an extreme price only
+            // restarts the pair's history
+            Err(_) => {
+                state.observations.clear();
+                Decimal::zero()
+            }
+        },
+        None => Decimal::zero(),
+    };
+    state.observations.push(TwapObservation {
+        time: now,
+        cumulative,
+        taker_amount,
+        maker_amount,
+    });
+    if state.observations.len() > MAX_TWAP_OBSERVATIONS {
+        state.observations.remove(0);
+    }
+    TWAP.save(storage, (base, quote), &state)
+}
+
+fn record_volume(storage: &mut dyn Storage, balance: &GenericBalance) -> StdResult<()> {
+    let assets = balance
+        .native
+        .iter()
+        .map(|coin| (coin.denom.as_str(), coin.amount))
+        .chain(
+            balance
+                .cw20
+                .iter()
+                .map(|token| (token.address.as_str(), token.amount)),
+        );
+    // Statistics saturate rather than fail, so they can never block a close
+    for (asset, amount) in assets {
+        let volume = VOLUME.may_load(storage, asset)?.unwrap_or_default();
+        VOLUME.save(storage, asset, &volume.saturating_add(amount))?;
+    }
+    Ok(())
+}
+
+/// Checks that the taker is allowed to close the order
+fn check_taker(
+    config: &Config,
+    order: &Order,
+    taker_address: &Addr,
+    ack: &Option<String>,
+) -> Result<(), ContractError> {
+    if config.blacklist.contains(taker_address) {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    // Reject if target address exists and is not equal to the order taker address
+    if let Some(target_address) = &order.target_address {
+        if taker_address != target_address {
+            return Err(ContractError::OrderReserved {});
+        }
+    };
+
+    if order.require_taker_ack.is_some() && *ack != order.require_taker_ack {
+        return Err(ContractError::AckMismatch {});
+    }
+    Ok(())
+}
+
+pub fn execute_close_orders(
+    mut deps: DepsMut,
+    env: Env,
+    funds: Vec<Coin>,
+    taker_address: &Addr,
+    order_ids: Vec<u64>,
+) -> Result<Response, ContractError> {
+    // Sum up the taker tokens of all orders, which must only demand native tokens
+    let config = CONFIG.load(deps.storage)?;
+    let mut demanded = GenericBalance::default();
+    let mut payments = vec![];
+    for order_id in order_ids {
+        let order = orders().load(deps.storage, order_id.into())?;
+        let taker_token = match order.price_in_usd {
+            Some(price_in_usd) => resolve_usd_demand(deps.as_ref(), &config, &order, price_in_usd)?,
+            None => order.taker_token,
+        };
+        if !taker_token.cw20.is_empty() {
+            return Err(ContractError::OrderUnmatched {});
+        }
+        let payment = Balance::try_from(&taker_token)?;
+        demanded.add_tokens(payment.clone());
+        payments.push((order_id, payment));
+    }
+
+    let mut attached = GenericBalance::default();
+    attached.add_tokens(Balance::from(funds));
+    if !attached.matches(&demanded) {
+        return Err(ContractError::OrderUnmatched {});
+    }
+
+    // Any failing order returns an error, which reverts the whole batch
+    let mut response = Response::new().add_attribute("method", "close_orders");
+    for (order_id, payment) in payments {
+        let res = execute_close_order(
+            deps.branch(),
+            env.clone(),
+            payment,
+            taker_address,
+            CloseOrderMsg {
+                order_id,
+                ..Default::default()
+            },
+        )?;
+        response = response
+            .add_attribute("order_id", order_id.to_string())
+            .add_events(res.events)
+            .add_submessages(res.messages);
+    }
+    Ok(response)
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(_deps: DepsMut, _env: Env, msg: Reply) -> Result<Response, ContractError> {
+    match msg.id {
+        // Only reached on error, the failed callback is logged and the fill kept
+        MAKER_CALLBACK_REPLY_ID => {
+            Ok(Response::new().add_attribute("method", "maker_callback_failed"))
+        }
+        id => Err(StdError::generic_err(format!("Unknown reply id {}", id)).into()),
+    }
+}
+
+/// Deposits the balance into a vault contract, crediting `recipient`
+fn deposit_tokens(
+    vault: &Addr,
+    recipient: &Addr,
+    balance: &GenericBalance,
+) -> StdResult<Vec<SubMsg>> {
+    let deposit = to_binary(&VaultMsg::Deposit {
+        recipient: recipient.to_string(),
+    })?;
+    let mut msgs = vec![];
+    if !balance.native.is_empty() {
+        msgs.push(SubMsg::new(WasmMsg::Execute {
+            contract_addr: vault.to_string(),
+            msg: deposit.clone(),
+            funds: balance.native.clone(),
+        }));
+    }
+    for token in &balance.cw20 {
+        let msg = Cw20ExecuteMsg::Send {
+            contract: vault.to_string(),
+            amount: token.amount,
+            msg: deposit.clone(),
+        };
+        msgs.push(SubMsg::new(WasmMsg::Execute {
+            contract_addr: token.address.to_string(),
+            msg: to_binary(&msg)?,
+            funds: vec![],
+        }));
+    }
+    Ok(msgs)
+}
+
+fn send_tokens(to: &Addr, balance: &GenericBalance) -> StdResult<Vec<SubMsg>> {
     let native_balance = &balance.native;
     let mut msgs: Vec<SubMsg> = if native_balance.is_empty() {
         vec![]
@@ -214,332 +1609,4946 @@ fn send_tokens(to: &Addr, balance: &GenericBalance) -> StdResult<Vec<SubMsg>> {
     Ok(msgs)
 }
 
-#[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
-    match msg {
-        QueryMsg::Order { id } => to_binary(&query_order(deps, id)?),
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    match msg {
+        QueryMsg::Order { id } => to_binary(&query_order(deps, id)?),
+        QueryMsg::SimulateOpen {
+            maker,
+            maker_token,
+            msg,
+        } => to_binary(&query_simulate_open(deps, env, maker, maker_token, msg)?),
+        QueryMsg::OrderMeta { id } => to_binary(&query_order_meta(deps, id)?),
+        QueryMsg::CloseFunds { order_id } => to_binary(&query_close_funds(deps, order_id)?),
+        QueryMsg::SettlementPreview { order_id } => {
+            to_binary(&query_settlement_preview(deps, order_id)?)
+        }
+        QueryMsg::OrdersByGroup {
+            maker,
+            group_id,
+            start_after,
+            limit,
+        } => to_binary(&query_orders_by_group(
+            deps,
+            maker,
+            group_id,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::ActiveAssets { start_after } => {
+            to_binary(&query_active_assets(deps, &env, start_after)?)
+        }
+        QueryMsg::AssetKind { order_id } => to_binary(&query_asset_kind(deps, order_id)?),
+        QueryMsg::BestOrder {
+            maker_denom,
+            taker_denom,
+            start_after,
+        } => to_binary(&query_best_order(
+            deps,
+            &env,
+            maker_denom,
+            taker_denom,
+            start_after,
+        )?),
+        QueryMsg::Depth {
+            base_denom,
+            taker_denom,
+            levels,
+            start_after,
+        } => to_binary(&query_depth(
+            deps,
+            &env,
+            base_denom,
+            taker_denom,
+            levels,
+            start_after,
+        )?),
+        QueryMsg::Search {
+            maker,
+            taker_denom,
+            open,
+            min_amount,
+            max_amount,
+            start_after,
+            limit,
+        } => to_binary(&query_search(
+            deps,
+            &env,
+            maker,
+            taker_denom,
+            open,
+            min_amount,
+            max_amount,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::ListOrders { start_after, limit } => {
+            to_binary(&query_list_orders(deps, start_after, limit)?)
+        }
+        QueryMsg::OrdersByMaker {
+            maker,
+            start_after,
+            limit,
+        } => to_binary(&query_orders_by_maker(deps, maker, start_after, limit)?),
+        QueryMsg::ListOrdersDesc {
+            start_before,
+            limit,
+        } => to_binary(&query_list_orders_desc(deps, start_before, limit)?),
+        QueryMsg::OrdersWithInvalidTarget { start_after, limit } => {
+            to_binary(&query_orders_with_invalid_target(deps, start_after, limit)?)
+        }
+        QueryMsg::IsBlacklisted { address } => to_binary(&query_is_blacklisted(deps, address)?),
+        QueryMsg::FillableCount {
+            balance,
+            start_after,
+        } => to_binary(&query_fillable_count(deps, &env, balance, start_after)?),
+        QueryMsg::Stats { start_after } => to_binary(&query_stats(deps, &env, start_after)?),
+        QueryMsg::Capabilities {} => to_binary(&query_capabilities(deps)?),
+        QueryMsg::RawOrderCount {} => to_binary(&query_raw_order_count(deps)?),
+        QueryMsg::Twap {
+            base,
+            quote,
+            window_seconds,
+        } => to_binary(&query_twap(deps, env, base, quote, window_seconds)?),
+    }
+}
+
+fn query_order(deps: Deps, id: u64) -> StdResult<OrderResponse> {
+    let order = orders()
+        .may_load(deps.storage, id.into())?
+        .ok_or_else(|| StdError::generic_err(format!("Order {} does not exist", id)))?;
+    order_response(id, order)
+}
+
+fn query_simulate_open(
+    deps: Deps,
+    env: Env,
+    maker: String,
+    maker_token: GenericBalance,
+    msg: OpenOrderMsg,
+) -> StdResult<SimulateOpenResponse> {
+    let maker = deps.api.addr_validate(&maker)?;
+    let config = CONFIG.load(deps.storage)?;
+    let implied_price = implied_price(
+        &maker_token,
+        &msg.taker_token.merged(),
+        &config.asset_decimals,
+    );
+    let would_expire_at = msg.expires_at_height;
+    let check = || -> Result<(), ContractError> {
+        // An open escrows either native funds or a single cw20 Send
+        match (maker_token.native.as_slice(), maker_token.cw20.as_slice()) {
+            ([], []) => return Err(ContractError::EmptyBalance {}),
+            (_, []) | ([], [_]) => {}
+            _ => {
+                return Err(StdError::generic_err(
+                    "Maker token must be native coins or a single cw20 token",
+                )
+                .into())
+            }
+        }
+        build_order(deps, &env, &config, &maker, maker_token, msg).map(|_| ())
+    };
+    let reason = check().err().map(|err| err.to_string());
+    Ok(SimulateOpenResponse {
+        valid: reason.is_none(),
+        reason,
+        implied_price,
+        would_expire_at,
+    })
+}
+
+fn query_order_meta(deps: Deps, id: u64) -> StdResult<OrderMetaResponse> {
+    let meta = ORDER_META.load(deps.storage, id.into())?;
+    Ok(OrderMetaResponse {
+        block_height: meta.block_height,
+        tx_index: meta.tx_index,
+    })
+}
+
+fn order_response(id: u64, order: Order) -> StdResult<OrderResponse> {
+    Ok(OrderResponse {
+        hash: order.hash()?,
+        id,
+        maker_address: order.maker_address,
+        maker_token: order.maker_token,
+        taker_token: order.taker_token,
+        target_address: order.target_address,
+        is_open: order.is_open,
+        memo: order.memo,
+        expires_at_height: order.expires_at_height,
+        price_in_usd: order.price_in_usd,
+        remainder_from_allowance: order.remainder_from_allowance,
+        group_id: order.group_id,
+        require_taker_ack: order.require_taker_ack,
+        deposit_to_vault: order.deposit_to_vault,
+    })
+}
+
+fn query_best_order(
+    deps: Deps,
+    env: &Env,
+    maker_denom: String,
+    taker_denom: String,
+    start_after: Option<u64>,
+) -> StdResult<BestOrderResponse> {
+    let (priced, next_start_after) =
+        priced_pair_orders(deps, env, &maker_denom, &taker_denom, start_after)?;
+    let mut best: Option<(Decimal, u64, Order)> = None;
+    for (price, id, order) in priced {
+        if !matches!(&best, Some((best_price, _, _)) if *best_price <= price) {
+            best = Some((price, id, order));
+        }
+    }
+    Ok(BestOrderResponse {
+        order: best
+            .map(|(_, id, order)| order_response(id, order))
+            .transpose()?,
+        next_start_after,
+    })
+}
+
+fn query_depth(
+    deps: Deps,
+    env: &Env,
+    base_denom: String,
+    taker_denom: String,
+    levels: u32,
+    start_after: Option<u64>,
+) -> StdResult<DepthResponse> {
+    let (priced, next_start_after) =
+        priced_pair_orders(deps, env, &base_denom, &taker_denom, start_after)?;
+    let mut depth: BTreeMap<Decimal, Uint128> = BTreeMap::new();
+    for (price, _, order) in priced {
+        if let Some((_, amount)) = order.offered_token().single_asset() {
+            let level = depth.entry(price).or_default();
+            *level = level.checked_add(amount)?;
+        }
+    }
+    let levels = depth
+        .into_iter()
+        .take(levels as usize)
+        .map(|(price, maker_amount)| DepthLevel {
+            price,
+            maker_amount,
+        })
+        .collect();
+    Ok(DepthResponse {
+        levels,
+        next_start_after,
+    })
+}
+
+/// Live single-asset orders offering `maker_denom` for `taker_denom` with their implied
+/// price, in ascending id order, from one bounded scan of the book along with the id to
+/// continue after. USD-priced orders have no price until they are closed.
+#[allow(clippy::type_complexity)]
+fn priced_pair_orders(
+    deps: Deps,
+    env: &Env,
+    maker_denom: &str,
+    taker_denom: &str,
+    start_after: Option<u64>,
+) -> StdResult<(Vec<(Decimal, u64, Order)>, Option<u64>)> {
+    let asset_decimals = CONFIG.load(deps.storage)?.asset_decimals;
+    let mut priced = vec![];
+    let next_start_after = scan_orders(deps.storage, start_after, |id, order| {
+        if !order.is_live(env.block.height) || order.price_in_usd.is_some() {
+            return Ok(true);
+        }
+        let is_pair = matches!(
+            (order.offered_token().single_asset(), order.taker_token.single_asset()),
+            (Some((maker, _)), Some((taker, _))) if maker == maker_denom && taker == taker_denom
+        );
+        if is_pair {
+            if let Some(price) = order.implied_price(&asset_decimals) {
+                priced.push((price, id, order));
+            }
+        }
+        Ok(true)
+    })?;
+    Ok((priced, next_start_after))
+}
+
+/// Visits orders after `start_after` in ascending id order until `visit` returns false
+/// or `MAX_SCAN` orders were visited. Returns the id of the last order visited when the
+/// scan stopped before the end of the book.
+fn scan_orders(
+    storage: &dyn Storage,
+    start_after: Option<u64>,
+    mut visit: impl FnMut(u64, Order) -> StdResult<bool>,
+) -> StdResult<Option<u64>> {
+    let start = start_after.map(|id| Bound::exclusive(U64Key::from(id)));
+    let mut last_scanned = None;
+    let mut more = true;
+    for (scanned, item) in orders()
+        .range(storage, start, None, cosmwasm_std::Order::Ascending)
+        .enumerate()
+    {
+        if scanned == MAX_SCAN || !more {
+            return Ok(last_scanned);
+        }
+        let (key, order) = item?;
+        let id = parse_order_id(&key)?;
+        last_scanned = Some(id);
+        more = visit(id, order)?;
+    }
+    Ok(None)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn query_search(
+    deps: Deps,
+    env: &Env,
+    maker: Option<String>,
+    taker_denom: Option<String>,
+    open: Option<bool>,
+    min_amount: Option<Uint128>,
+    max_amount: Option<Uint128>,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<SearchResponse> {
+    let maker = maker
+        .map(|maker| deps.api.addr_validate(&maker))
+        .transpose()?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    let is_match = |order: &Order| {
+        if matches!(&maker, Some(maker) if *maker != order.maker_address) {
+            return false;
+        }
+        if matches!(open, Some(open) if open != order.is_live(env.block.height)) {
+            return false;
+        }
+        if let Some(denom) = &taker_denom {
+            let demanded = order
+                .taker_token
+                .native
+                .iter()
+                .any(|coin| coin.denom == *denom)
+                || order
+                    .taker_token
+                    .cw20
+                    .iter()
+                    .any(|token| token.address == *denom);
+            if !demanded {
+                return false;
+            }
+        }
+        if min_amount.is_some() || max_amount.is_some() {
+            let amount = match order.maker_token.single_asset() {
+                Some((_, amount)) => amount,
+                None => return false,
+            };
+            if matches!(min_amount, Some(min) if amount < min)
+                || matches!(max_amount, Some(max) if amount > max)
+            {
+                return false;
+            }
+        }
+        true
+    };
+
+    let mut matched = vec![];
+    let next_start_after = scan_orders(deps.storage, start_after, |id, order| {
+        if is_match(&order) {
+            matched.push(order_response(id, order)?);
+        }
+        Ok(matched.len() < limit)
+    })?;
+    Ok(SearchResponse {
+        orders: matched,
+        next_start_after,
+    })
+}
+
+fn query_list_orders(
+    deps: Deps,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<OrdersResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(|id| Bound::exclusive(U64Key::from(id)));
+    let orders = orders()
+        .range(deps.storage, start, None, cosmwasm_std::Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (key, order) = item?;
+            order_response(parse_order_id(&key)?, order)
+        })
+        .collect::<StdResult<_>>()?;
+    Ok(OrdersResponse { orders })
+}
+
+fn query_orders_by_maker(
+    deps: Deps,
+    maker: String,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<OrdersResponse> {
+    let maker = deps.api.addr_validate(&maker)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(|id| Bound::exclusive(U64Key::from(id)));
+    let orders = orders()
+        .idx
+        .maker
+        .prefix(maker.as_bytes().to_vec())
+        .range(deps.storage, start, None, cosmwasm_std::Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (key, order) = item?;
+            order_response(parse_order_id(&key)?, order)
+        })
+        .collect::<StdResult<_>>()?;
+    Ok(OrdersResponse { orders })
+}
+
+fn query_list_orders_desc(
+    deps: Deps,
+    start_before: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<OrdersResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let end = start_before.map(|id| Bound::exclusive(U64Key::from(id)));
+    let orders = orders()
+        .range(deps.storage, None, end, cosmwasm_std::Order::Descending)
+        .take(limit)
+        .map(|item| {
+            let (key, order) = item?;
+            order_response(parse_order_id(&key)?, order)
+        })
+        .collect::<StdResult<_>>()?;
+    Ok(OrdersResponse { orders })
+}
+
+fn query_orders_with_invalid_target(
+    deps: Deps,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<SearchResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let mut matched = vec![];
+    let next_start_after = scan_orders(deps.storage, start_after, |id, order| {
+        let invalid = matches!(
+            &order.target_address,
+            Some(target) if deps.api.addr_validate(target.as_str()).is_err()
+        );
+        if order.is_open && invalid {
+            matched.push(order_response(id, order)?);
+        }
+        Ok(matched.len() < limit)
+    })?;
+    Ok(SearchResponse {
+        orders: matched,
+        next_start_after,
+    })
+}
+
+fn query_orders_by_group(
+    deps: Deps,
+    maker: String,
+    group_id: String,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<OrdersResponse> {
+    let maker = deps.api.addr_validate(&maker)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let orders = orders()
+        .idx
+        .group
+        .order_ids(deps.storage, &maker, &group_id, start_after)
+        .take(limit)
+        .map(|item| {
+            let order_id = item?;
+            order_response(order_id, orders().load(deps.storage, order_id.into())?)
+        })
+        .collect::<StdResult<_>>()?;
+    Ok(OrdersResponse { orders })
+}
+
+fn query_is_blacklisted(deps: Deps, address: String) -> StdResult<IsBlacklistedResponse> {
+    let address = deps.api.addr_validate(&address)?;
+    let blacklist = CONFIG.load(deps.storage)?.blacklist;
+    Ok(IsBlacklistedResponse {
+        blacklisted: blacklist.contains(&address),
+    })
+}
+
+fn query_fillable_count(
+    deps: Deps,
+    env: &Env,
+    balance: GenericBalance,
+    start_after: Option<u64>,
+) -> StdResult<FillableCountResponse> {
+    let mut count = 0;
+    let next_start_after = scan_orders(deps.storage, start_after, |_, order| {
+        // Each order is checked against the whole balance, as if it were the only fill.
+        // The taker token of a USD-priced order is only a placeholder.
+        if order.is_live(env.block.height)
+            && order.price_in_usd.is_none()
+            && balance.checked_sub(&order.taker_token).is_some()
+        {
+            count += 1;
+        }
+        Ok(true)
+    })?;
+    Ok(FillableCountResponse {
+        count,
+        next_start_after,
+    })
+}
+
+fn query_stats(deps: Deps, env: &Env, start_after: Option<u64>) -> StdResult<StatsResponse> {
+    let mut total_orders = 0;
+    let mut open_orders = 0;
+    let mut expired_orders = 0;
+    let next_start_after = scan_orders(deps.storage, start_after, |_, order| {
+        total_orders += 1;
+        if order.is_live(env.block.height) {
+            open_orders += 1;
+        } else if order.is_open {
+            expired_orders += 1;
+        }
+        Ok(true)
+    })?;
+    let volume = VOLUME
+        .range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
+        .map(|item| {
+            let (asset, amount) = item?;
+            Ok((String::from_utf8(asset)?, amount))
+        })
+        .collect::<StdResult<_>>()?;
+    let active = query_active_assets(deps, env, start_after)?;
+    Ok(StatsResponse {
+        total_orders,
+        open_orders,
+        expired_orders,
+        closed_orders: total_orders - open_orders - expired_orders,
+        volume,
+        active_assets: (active.native_denoms.len() + active.cw20_contracts.len()) as u64,
+        next_start_after,
+    })
+}
+
+fn query_capabilities(deps: Deps) -> StdResult<CapabilitiesResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    Ok(CapabilitiesResponse {
+        partial_fills: false,
+        nft_support: false,
+        fees: false,
+        pause: false,
+        open_cooldown: config.open_cooldown_blocks.is_some(),
+        denom_aliases: !config.denom_aliases.is_empty(),
+        blacklist: !config.blacklist.is_empty(),
+        usd_pricing: config.oracle.is_some(),
+        min_prices: !config.min_prices.is_empty(),
+    })
+}
+
+fn query_raw_order_count(deps: Deps) -> StdResult<RawOrderCountResponse> {
+    let count = ORDER_COUNT.may_load(deps.storage)?.unwrap_or_default();
+    Ok(RawOrderCountResponse { count })
+}
+
+fn query_twap(
+    deps: Deps,
+    env: Env,
+    base: String,
+    quote: String,
+    window_seconds: u64,
+) -> StdResult<TwapResponse> {
+    let state = TWAP
+        .may_load(deps.storage, (&base, &quote))?
+        .unwrap_or_default();
+    let last = state
+        .observations
+        .last()
+        .ok_or_else(|| StdError::generic_err("No price history for pair"))?;
+    if window_seconds == 0 {
+        return Ok(TwapResponse {
+            price: checked_ratio(last.taker_amount, last.maker_amount)
+                .ok_or_else(|| StdError::generic_err("Price overflow"))?,
+        });
+    }
+
+    let now = env.block.time.seconds();
+    let start = now.saturating_sub(window_seconds);
+    let first = &state.observations[0];
+    if now < last.time || start < first.time {
+        return Err(StdError::generic_err("Not enough price history for window"));
+    }
+    // The latest observation at or before the window start holds the price at that time
+    let before_start = state
+        .observations
+        .iter()
+        .rev()
+        .find(|observation| observation.time <= start)
+        .unwrap_or(first);
+
+    let elapsed = last.cumulative_at(now)? - before_start.cumulative_at(start)?;
+    Ok(TwapResponse {
+        price: elapsed / Uint128::from(window_seconds),
+    })
+}
+
+/// Decodes the big-endian u64 key orders are stored under
+fn parse_order_id(key: &[u8]) -> StdResult<u64> {
+    let bytes: [u8; 8] = key
+        .try_into()
+        .map_err(|_| StdError::generic_err("Corrupted order key"))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn query_close_funds(deps: Deps, order_id: u64) -> StdResult<CloseFundsResponse> {
+    let order = orders().load(deps.storage, order_id.into())?;
+    let taker_token = match order.price_in_usd {
+        Some(price_in_usd) => {
+            let config = CONFIG.load(deps.storage)?;
+            resolve_usd_demand(deps, &config, &order, price_in_usd)
+                .map_err(|err| StdError::generic_err(err.to_string()))?
+        }
+        None => order.taker_token,
+    };
+    Ok(CloseFundsResponse {
+        funds: taker_token.native,
+        cw20: !taker_token.cw20.is_empty(),
+    })
+}
+
+fn query_settlement_preview(deps: Deps, order_id: u64) -> StdResult<SettlementPreviewResponse> {
+    let order = orders().load(deps.storage, order_id.into())?;
+    if !order.is_open {
+        return Err(StdError::generic_err(
+            ContractError::OrderClosed {}.to_string(),
+        ));
+    }
+    let taker_token = match order.price_in_usd {
+        Some(price_in_usd) => {
+            let config = CONFIG.load(deps.storage)?;
+            resolve_usd_demand(deps, &config, &order, price_in_usd)
+                .map_err(|err| StdError::generic_err(err.to_string()))?
+        }
+        None => order.taker_token.clone(),
+    };
+
+    // Same order as the messages emitted by execute_close_order
+    let maker = match &order.deposit_to_vault {
+        Some(vault) => SettlementRecipient::Vault {
+            address: vault.clone(),
+        },
+        None => SettlementRecipient::Maker {
+            address: order.maker_address.clone(),
+        },
+    };
+    let mut transfers = settlement_transfers(&maker, &taker_token);
+    transfers.append(&mut settlement_transfers(
+        &SettlementRecipient::Taker {},
+        &order.maker_token,
+    ));
+    if let Some(remainder) = order.remainder_from_allowance {
+        transfers.push(SettlementTransfer {
+            recipient: SettlementRecipient::Taker {},
+            asset: remainder.address.to_string(),
+            amount: remainder.amount,
+        });
+    }
+    Ok(SettlementPreviewResponse { transfers })
+}
+
+fn settlement_transfers(
+    recipient: &SettlementRecipient,
+    balance: &GenericBalance,
+) -> Vec<SettlementTransfer> {
+    let native = balance.native.iter().map(|coin| SettlementTransfer {
+        recipient: recipient.clone(),
+        asset: coin.denom.clone(),
+        amount: coin.amount,
+    });
+    let cw20 = balance.cw20.iter().map(|token| SettlementTransfer {
+        recipient: recipient.clone(),
+        asset: token.address.to_string(),
+        amount: token.amount,
+    });
+    native.chain(cw20).collect()
+}
+
+fn query_asset_kind(deps: Deps, order_id: u64) -> StdResult<AssetKindResponse> {
+    let order = orders().load(deps.storage, order_id.into())?;
+    // Orders demand either native tokens or a single cw20 token
+    Ok(match order.taker_token.cw20.first() {
+        Some(token) => AssetKindResponse::Cw20 {
+            contract: token.address.clone(),
+        },
+        None => AssetKindResponse::Native {
+            denoms: order
+                .taker_token
+                .native
+                .into_iter()
+                .map(|c| c.denom)
+                .collect(),
+        },
+    })
+}
+
+fn query_active_assets(
+    deps: Deps,
+    env: &Env,
+    start_after: Option<u64>,
+) -> StdResult<ActiveAssetsResponse> {
+    let mut native_denoms = BTreeSet::new();
+    let mut cw20_contracts = BTreeSet::new();
+    let next_start_after = scan_orders(deps.storage, start_after, |_, order| {
+        if order.is_live(env.block.height) {
+            for balance in [&order.maker_token, &order.taker_token] {
+                native_denoms.extend(balance.native.iter().map(|c| c.denom.clone()));
+                cw20_contracts.extend(balance.cw20.iter().map(|c| c.address.clone()));
+            }
+        }
+        Ok(true)
+    })?;
+    Ok(ActiveAssetsResponse {
+        native_denoms: native_denoms.into_iter().collect(),
+        cw20_contracts: cw20_contracts.into_iter().collect(),
+        next_start_after,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{
+        mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage,
+    };
+    use cosmwasm_std::{
+        coin, coins, ContractResult, CosmosMsg, Empty, OwnedDeps, QuerierResult, SystemError,
+        SystemResult, TransactionInfo, Uint128, WasmQuery,
+    };
+    use cw0::NativeBalance;
+    use cw20::{Cw20Coin, Expiration};
+
+    #[test]
+    fn order_native_to_cw20() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let cw20_token_amount = Uint128::new(12345);
+        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, cw20_token_amount);
+
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens.clone(),
+            target_address: None,
+            ..Default::default()
+        };
+        let maker = String::from("maker");
+        let balance = coins(100, "native");
+        let info = mock_info(&maker, &balance);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::OpenOrder(msg.clone()),
+        )
+        .unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("method", "open_order"), res.attributes[0]);
+        assert_eq!(("order_id", "1"), res.attributes[1]);
+        assert_eq!(("maker_address", "maker"), res.attributes[2]);
+
+        // Check that order is correctly opened
+        let order = query_order(deps.as_ref(), 1).unwrap();
+        assert_eq!(&maker, order.maker_address.as_str());
+        assert_eq!(balance, order.maker_token.native);
+        assert_eq!(cw20_tokens.cw20, order.taker_token.cw20);
+        assert_eq!(None, order.target_address);
+        assert_eq!(true, order.is_open);
+
+        // Close the open order
+        let taker = String::from("taker");
+        let receive = Cw20ReceiveMsg {
+            sender: taker.clone(),
+            amount: cw20_token_amount,
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        let msg = ExecuteMsg::Receive(receive.clone());
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(2, res.messages.len());
+        assert_eq!(("method", "close_order"), res.attributes[0]);
+        assert_eq!(("order_id", "1"), res.attributes[1]);
+        assert_eq!(("maker_address", "maker"), res.attributes[2]);
+        assert_eq!(("taker_address", "taker"), res.attributes[3]);
+        let send_msg = Cw20ExecuteMsg::Transfer {
+            recipient: maker,
+            amount: cw20_token_amount,
+        };
+        assert_eq!(
+            res.messages[0],
+            SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: cw20_token_contract.clone(),
+                msg: to_binary(&send_msg).unwrap(),
+                funds: vec![]
+            }))
+        );
+        assert_eq!(
+            res.messages[1],
+            SubMsg::new(BankMsg::Send {
+                to_address: taker,
+                amount: balance,
+            })
+        );
+
+        // Check that order is closed
+        let order = query_order(deps.as_ref(), 1).unwrap();
+        assert_eq!(false, order.is_open);
+    }
+
+    #[test]
+    fn order_cw20_to_native() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let mut native_tokens = GenericBalance::default();
+        native_tokens.add_tokens(Balance::Native(NativeBalance(coins(100, "native"))));
+        let msg = OpenOrderMsg {
+            taker_token: native_tokens.clone(),
+            target_address: None,
+            ..Default::default()
+        };
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let cw20_token_amount = Uint128::new(12345);
+        let maker = String::from("maker");
+        let receive = Cw20ReceiveMsg {
+            sender: maker.clone(),
+            amount: cw20_token_amount,
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        let msg = ExecuteMsg::Receive(receive.clone());
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("method", "open_order"), res.attributes[0]);
+        assert_eq!(("order_id", "1"), res.attributes[1]);
+
+        // Check that order is correctly opened
+        let order = query_order(deps.as_ref(), 1).unwrap();
+        assert_eq!(maker, order.maker_address.as_str());
+        assert_eq!(
+            create_cw20_tokens(&cw20_token_contract, cw20_token_amount).cw20,
+            order.maker_token.cw20
+        );
+        assert_eq!(native_tokens.native, order.taker_token.native);
+        assert_eq!(None, order.target_address);
+        assert_eq!(true, order.is_open);
+
+        // Close the open order
+        let taker = String::from("taker");
+        let balance = coins(100, "native");
+        let info = mock_info(&taker, &balance);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+        assert_eq!(2, res.messages.len());
+        assert_eq!(("method", "close_order"), res.attributes[0]);
+        assert_eq!(("order_id", "1"), res.attributes[1]);
+        assert_eq!(
+            res.messages[0],
+            SubMsg::new(BankMsg::Send {
+                to_address: maker,
+                amount: balance,
+            })
+        );
+        let send_msg = Cw20ExecuteMsg::Transfer {
+            recipient: taker,
+            amount: cw20_token_amount,
+        };
+        assert_eq!(
+            res.messages[1],
+            SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: cw20_token_contract.clone(),
+                msg: to_binary(&send_msg).unwrap(),
+                funds: vec![]
+            }))
+        );
+
+        // Check that order is closed
+        let order = query_order(deps.as_ref(), 1).unwrap();
+        assert_eq!(false, order.is_open);
+    }
+
+    #[test]
+    fn order_cw20_to_cw20() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let xyz_token_contract = String::from("xyz-token");
+        let xyz_token_amount = Uint128::new(12345);
+        let xyz_tokens = create_cw20_tokens(&xyz_token_contract, xyz_token_amount);
+        let msg = OpenOrderMsg {
+            taker_token: xyz_tokens.clone(),
+            target_address: None,
+            ..Default::default()
+        };
+
+        let abc_token_contract = String::from("abc-token");
+        let abc_token_amount = Uint128::new(12345);
+        let maker = String::from("maker");
+        let receive = Cw20ReceiveMsg {
+            sender: maker.clone(),
+            amount: abc_token_amount,
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info(&abc_token_contract, &[]);
+        let msg = ExecuteMsg::Receive(receive.clone());
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("method", "open_order"), res.attributes[0]);
+        assert_eq!(("order_id", "1"), res.attributes[1]);
+
+        // Check that order is correctly opened
+        let order = query_order(deps.as_ref(), 1).unwrap();
+        assert_eq!(maker, order.maker_address.as_str());
+        assert_eq!(
+            create_cw20_tokens(&abc_token_contract, abc_token_amount).cw20,
+            order.maker_token.cw20
+        );
+        assert_eq!(xyz_tokens.cw20, order.taker_token.cw20);
+        assert_eq!(None, order.target_address);
+        assert_eq!(true, order.is_open);
+
+        // Close the open order
+        let taker = String::from("taker");
+        let receive = Cw20ReceiveMsg {
+            sender: taker.clone(),
+            amount: xyz_token_amount,
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&xyz_token_contract, &[]);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+        assert_eq!(2, res.messages.len());
+        assert_eq!(("method", "close_order"), res.attributes[0]);
+        assert_eq!(("order_id", "1"), res.attributes[1]);
+        let send_msg_0 = Cw20ExecuteMsg::Transfer {
+            recipient: maker,
+            amount: xyz_token_amount,
+        };
+        assert_eq!(
+            res.messages[0],
+            SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: xyz_token_contract,
+                msg: to_binary(&send_msg_0).unwrap(),
+                funds: vec![]
+            }))
+        );
+        let send_msg_1 = Cw20ExecuteMsg::Transfer {
+            recipient: taker,
+            amount: abc_token_amount,
+        };
+        assert_eq!(
+            res.messages[1],
+            SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: abc_token_contract,
+                msg: to_binary(&send_msg_1).unwrap(),
+                funds: vec![]
+            }))
+        );
+
+        // Check that order is closed
+        let order = query_order(deps.as_ref(), 1).unwrap();
+        assert_eq!(false, order.is_open);
+    }
+
+    #[test]
+    fn open_multiple_orders() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let cw20_token_amount = Uint128::new(12345);
+        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, cw20_token_amount);
+
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens.clone(),
+            target_address: None,
+            ..Default::default()
+        };
+        let maker = String::from("maker");
+        let first_order_balance = coins(100, "native");
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(&maker, &first_order_balance),
+            ExecuteMsg::OpenOrder(msg.clone()),
+        )
+        .unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("method", "open_order"), res.attributes[0]);
+        assert_eq!(("order_id", "1"), res.attributes[1]);
+
+        let second_order_balance = coins(200, "native");
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(&maker, &second_order_balance),
+            ExecuteMsg::OpenOrder(msg.clone()),
+        )
+        .unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("method", "open_order"), res.attributes[0]);
+        assert_eq!(("order_id", "2"), res.attributes[1]);
+
+        let third_order_balance = coins(300, "native");
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(&maker, &third_order_balance),
+            ExecuteMsg::OpenOrder(msg.clone()),
+        )
+        .unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("method", "open_order"), res.attributes[0]);
+        assert_eq!(("order_id", "3"), res.attributes[1]);
+
+        // Check that orders are correctly opened
+        let order = query_order(deps.as_ref(), 1).unwrap();
+        assert_eq!(first_order_balance, order.maker_token.native);
+        let order = query_order(deps.as_ref(), 2).unwrap();
+        assert_eq!(second_order_balance, order.maker_token.native);
+        let order = query_order(deps.as_ref(), 3).unwrap();
+        assert_eq!(third_order_balance, order.maker_token.native);
+    }
+
+    #[test]
+    fn close_order_with_invalid_target_address_fails() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let cw20_token_amount = Uint128::new(12345);
+        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, cw20_token_amount);
+
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens.clone(),
+            target_address: Some(String::from("target")),
+            ..Default::default()
+        };
+        let maker = String::from("maker");
+        let balance = coins(100, "native");
+        let info = mock_info(&maker, &balance);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::OpenOrder(msg.clone()),
+        )
+        .unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("method", "open_order"), res.attributes[0]);
+        assert_eq!(("order_id", "1"), res.attributes[1]);
+
+        // Close the open order
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(12345),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        let msg = ExecuteMsg::Receive(receive.clone());
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::OrderReserved {}));
+    }
+
+    #[test]
+    fn close_order_with_valid_target_address_succeeds() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let cw20_token_amount = Uint128::new(12345);
+        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, cw20_token_amount);
+
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens.clone(),
+            target_address: Some(String::from("target")),
+            ..Default::default()
+        };
+        let maker = String::from("maker");
+        let balance = coins(100, "native");
+        let info = mock_info(&maker, &balance);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::OpenOrder(msg.clone()),
+        )
+        .unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("method", "open_order"), res.attributes[0]);
+        assert_eq!(("order_id", "1"), res.attributes[1]);
+
+        // Close the open order
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("target"),
+            amount: Uint128::new(12345),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        let msg = ExecuteMsg::Receive(receive.clone());
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(2, res.messages.len());
+        assert_eq!(("method", "close_order"), res.attributes[0]);
+        assert_eq!(("order_id", "1"), res.attributes[1]);
+        assert_eq!(("maker_address", "maker"), res.attributes[2]);
+        assert_eq!(("taker_address", "target"), res.attributes[3]);
+        assert_eq!(("target_address", "target"), res.attributes[4]);
+    }
+
+    #[test]
+    fn target_address_is_stored_normalized() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_tokens = create_cw20_tokens(&String::from("my-cw20-token"), 12345u128.into());
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens,
+            target_address: Some(String::from("TarGet")),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        // The stored target is what the chain would report as the closing sender
+        let api = deps.api;
+        let target = api
+            .addr_humanize(&api.addr_canonicalize("TarGet").unwrap())
+            .unwrap();
+        let order = query_order(deps.as_ref(), 1).unwrap();
+        assert_eq!(Some(target.clone()), order.target_address);
+
+        let receive = Cw20ReceiveMsg {
+            sender: target.to_string(),
+            amount: Uint128::new(12345),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+        assert_eq!(("method", "close_order"), res.attributes[0]);
+    }
+
+    #[test]
+    fn reserved_order_cw20_to_native() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let mut native_tokens = GenericBalance::default();
+        native_tokens.add_tokens(Balance::Native(NativeBalance(coins(100, "native"))));
+        let msg = OpenOrderMsg {
+            taker_token: native_tokens,
+            target_address: Some(String::from("target")),
+            ..Default::default()
+        };
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let cw20_token_amount = Uint128::new(12345);
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: cw20_token_amount,
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+
+        let order = query_order(deps.as_ref(), 1).unwrap();
+        assert_eq!(Some(Addr::unchecked("target")), order.target_address);
+
+        // Anyone but the target is rejected
+        let balance = coins(100, "native");
+        let msg = ExecuteMsg::CloseOrder(CloseOrderMsg {
+            order_id: 1,
+            ..Default::default()
+        });
+        let info = mock_info("taker", &balance);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::OrderReserved {}));
+
+        // The target closes the order with native tokens
+        let info = mock_info("target", &balance);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(2, res.messages.len());
+        assert_eq!(
+            res.messages[0],
+            SubMsg::new(BankMsg::Send {
+                to_address: String::from("maker"),
+                amount: balance,
+            })
+        );
+        let send_msg = Cw20ExecuteMsg::Transfer {
+            recipient: String::from("target"),
+            amount: cw20_token_amount,
+        };
+        assert_eq!(
+            res.messages[1],
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: cw20_token_contract,
+                msg: to_binary(&send_msg).unwrap(),
+                funds: vec![]
+            })
+        );
+        assert!(!query_order(deps.as_ref(), 1).unwrap().is_open);
+    }
+
+    #[test]
+    fn open_order_with_target_and_memo_via_receive() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let mut native_tokens = GenericBalance::default();
+        native_tokens.add_tokens(Balance::Native(NativeBalance(coins(100, "native"))));
+        let msg = OpenOrderMsg {
+            taker_token: native_tokens,
+            target_address: Some(String::from("target")),
+            memo: Some(String::from("otc deal #42")),
+            ..Default::default()
+        };
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(12345),
+            msg: to_binary(&ReceiveMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+
+        let order = query_order(deps.as_ref(), 1).unwrap();
+        assert_eq!(Some(Addr::unchecked("target")), order.target_address);
+        assert_eq!(Some(String::from("otc deal #42")), order.memo);
+    }
+
+    #[test]
+    fn close_order_with_invalid_token_fails() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let cw20_token_amount = Uint128::new(12345);
+        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, cw20_token_amount);
+
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens.clone(),
+            target_address: None,
+            ..Default::default()
+        };
+        let maker = String::from("maker");
+        let balance = coins(100, "native");
+        let info = mock_info(&maker, &balance);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::OpenOrder(msg.clone()),
+        )
+        .unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("method", "open_order"), res.attributes[0]);
+        assert_eq!(("order_id", "1"), res.attributes[1]);
+
+        // Try to close the open order with a wrong token
+        let wrong_token_contract = String::from("wrong-token");
+        let wrong_token_amount = Uint128::new(12345);
+
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: wrong_token_amount,
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&wrong_token_contract, &[]);
+        let msg = ExecuteMsg::Receive(receive.clone());
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::OrderUnmatched {}));
+    }
+
+    #[test]
+    fn close_closed_order_via_receive_fails() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let cw20_token_amount = Uint128::new(12345);
+        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, cw20_token_amount);
+
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens.clone(),
+            target_address: None,
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        // Close the open order
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: cw20_token_amount,
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        let msg = ExecuteMsg::Receive(receive.clone());
+        execute(deps.as_mut(), mock_env(), info.clone(), msg.clone()).unwrap();
+
+        // Closing it again must fail so that the second transfer is reverted
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::OrderClosed {}));
+    }
+
+    #[test]
+    fn query_active_assets_deduplicates() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, Uint128::new(12345));
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens,
+            target_address: None,
+            ..Default::default()
+        };
+        for balance in [
+            coins(100, "native"),
+            coins(200, "native"),
+            coins(300, "other"),
+        ] {
+            let info = mock_info("maker", &balance);
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                info,
+                ExecuteMsg::OpenOrder(msg.clone()),
+            )
+            .unwrap();
+        }
+
+        let res = query_active_assets(deps.as_ref(), &mock_env(), None).unwrap();
+        assert_eq!(
+            vec![String::from("native"), String::from("other")],
+            res.native_denoms
+        );
+        assert_eq!(
+            vec![Addr::unchecked(cw20_token_contract)],
+            res.cw20_contracts
+        );
+    }
+
+    #[test]
+    fn close_multiple_native_orders() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let mut native_tokens = GenericBalance::default();
+        native_tokens.add_tokens(Balance::Native(NativeBalance(coins(100, "native"))));
+        let msg = OpenOrderMsg {
+            taker_token: native_tokens,
+            target_address: None,
+            ..Default::default()
+        };
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        for maker in ["maker1", "maker2"] {
+            let receive = Cw20ReceiveMsg {
+                sender: String::from(maker),
+                amount: Uint128::new(12345),
+                msg: to_binary(&ExecuteMsg::OpenOrder(msg.clone())).unwrap(),
+            };
+            let info = mock_info(&cw20_token_contract, &[]);
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                info,
+                ExecuteMsg::Receive(receive),
+            )
+            .unwrap();
+        }
+
+        // Paying for only one of the orders fails
+        let taker = String::from("taker");
+        let msg = ExecuteMsg::CloseOrders {
+            order_ids: vec![1, 2],
+        };
+        let info = mock_info(&taker, &coins(100, "native"));
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::OrderUnmatched {}));
+
+        // Close both orders at once
+        let info = mock_info(&taker, &coins(200, "native"));
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(4, res.messages.len());
+        assert_eq!(("method", "close_orders"), res.attributes[0]);
+        assert_eq!(("order_id", "1"), res.attributes[1]);
+        assert_eq!(("order_id", "2"), res.attributes[2]);
+
+        // The details of each close are on its event
+        assert_eq!(2, res.events.len());
+        let event = &res.events[1];
+        assert_eq!("order_closed", event.ty);
+        assert_eq!(("order_id", "2"), event.attributes[0]);
+        assert_eq!(("maker_address", "maker2"), event.attributes[1]);
+        assert_eq!(("taker_address", "taker"), event.attributes[2]);
+        assert_eq!("exec_price", event.attributes[3].key);
+        assert_eq!(
+            res.messages[0],
+            SubMsg::new(BankMsg::Send {
+                to_address: String::from("maker1"),
+                amount: coins(100, "native"),
+            })
+        );
+        assert_eq!(
+            res.messages[2],
+            SubMsg::new(BankMsg::Send {
+                to_address: String::from("maker2"),
+                amount: coins(100, "native"),
+            })
+        );
+
+        // Check that both orders are closed
+        assert!(!query_order(deps.as_ref(), 1).unwrap().is_open);
+        assert!(!query_order(deps.as_ref(), 2).unwrap().is_open);
+    }
+
+    #[test]
+    fn close_order_with_multiple_cw20_deposits() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, Uint128::new(100));
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens,
+            target_address: None,
+            ..Default::default()
+        };
+        let balance = coins(100, "native");
+        let info = mock_info("maker", &balance);
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        // A partial deposit is held without closing the order
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(40),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("method", "deposit"), res.attributes[0]);
+        assert_eq!(("deposited", "40"), res.attributes[2]);
+        assert!(query_order(deps.as_ref(), 1).unwrap().is_open);
+
+        // Another taker's deposit is refunded once the order is settled
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("other"),
+            amount: Uint128::new(10),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let msg = ExecuteMsg::Receive(receive);
+        execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        // The second deposit meets the demand and settles the order
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(60),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let msg = ExecuteMsg::Receive(receive);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(3, res.messages.len());
+        assert_eq!(("method", "close_order"), res.attributes[0]);
+        let send_msg = Cw20ExecuteMsg::Transfer {
+            recipient: String::from("maker"),
+            amount: Uint128::new(100),
+        };
+        assert_eq!(
+            res.messages[0],
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: cw20_token_contract.clone(),
+                msg: to_binary(&send_msg).unwrap(),
+                funds: vec![]
+            })
+        );
+        assert_eq!(
+            res.messages[1],
+            SubMsg::new(BankMsg::Send {
+                to_address: String::from("taker"),
+                amount: balance,
+            })
+        );
+        let refund_msg = Cw20ExecuteMsg::Transfer {
+            recipient: String::from("other"),
+            amount: Uint128::new(10),
+        };
+        assert_eq!(
+            res.messages[2],
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: cw20_token_contract,
+                msg: to_binary(&refund_msg).unwrap(),
+                funds: vec![]
+            })
+        );
+        assert!(!query_order(deps.as_ref(), 1).unwrap().is_open);
+    }
+
+    #[test]
+    fn order_events_include_order_id() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let cw20_token_amount = Uint128::new(12345);
+        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, cw20_token_amount);
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens,
+            target_address: None,
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        let res = execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        assert_eq!(1, res.events.len());
+        assert_eq!("order_opened", res.events[0].ty);
+        assert_eq!(("order_id", "1"), res.events[0].attributes[0]);
+
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: cw20_token_amount,
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        let msg = ExecuteMsg::Receive(receive);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(1, res.events.len());
+        assert_eq!("order_closed", res.events[0].ty);
+        assert_eq!(("order_id", "1"), res.events[0].attributes[0]);
+    }
+
+    #[test]
+    fn reclaim_pending_deposit() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, Uint128::new(100));
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens,
+            target_address: None,
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(40),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        let msg = ExecuteMsg::Receive(receive);
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // Someone else has nothing to reclaim
+        let msg = ExecuteMsg::ReclaimDeposit { order_id: 1 };
+        let info = mock_info("other", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::NoPendingDeposit {}));
+
+        let info = mock_info("taker", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), msg.clone()).unwrap();
+        assert_eq!(("method", "reclaim_deposit"), res.attributes[0]);
+        let send_msg = Cw20ExecuteMsg::Transfer {
+            recipient: String::from("taker"),
+            amount: Uint128::new(40),
+        };
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(WasmMsg::Execute {
+                contract_addr: cw20_token_contract,
+                msg: to_binary(&send_msg).unwrap(),
+                funds: vec![]
+            })]
+        );
+
+        // The entry is cleared
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::NoPendingDeposit {}));
+        assert!(query_order(deps.as_ref(), 1).unwrap().is_open);
+    }
+
+    #[test]
+    fn invalidate_unbacked_closes_order_maker_cannot_cover() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        for remainder in [50u128, 90] {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+                remainder_from_allowance: Some(Cw20Coin {
+                    address: String::from("abc-token"),
+                    amount: Uint128::new(remainder),
+                }),
+                ..Default::default()
+            };
+            let info = mock_info("maker", &coins(10, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+        // The maker's holdings drop below the second remainder
+        deps.querier.update_wasm(mock_wasm(50));
+
+        let info = mock_info("keeper", &[]);
+        let msg = ExecuteMsg::InvalidateUnbacked { order_id: 1 };
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap_err();
+        assert!(matches!(err, ContractError::OrderBacked {}));
+
+        // The bond of the unbacked order is forfeited to the caller
+        let msg = ExecuteMsg::InvalidateUnbacked { order_id: 2 };
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(BankMsg::Send {
+                to_address: String::from("keeper"),
+                amount: coins(10, "native"),
+            })]
+        );
+        assert!(query_order(deps.as_ref(), 1).unwrap().is_open);
+        assert!(!query_order(deps.as_ref(), 2).unwrap().is_open);
+
+        // Revoking the allowance leaves the order unbacked despite the balance
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::Smart { msg, .. }
+                if matches!(from_binary(msg), Ok(Cw20QueryMsg::Allowance { .. })) =>
+            {
+                let allowance = AllowanceResponse {
+                    allowance: Uint128::zero(),
+                    expires: Expiration::Never {},
+                };
+                SystemResult::Ok(ContractResult::Ok(to_binary(&allowance).unwrap()))
+            }
+            query => mock_wasm(50)(query),
+        });
+        let msg = ExecuteMsg::InvalidateUnbacked { order_id: 1 };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert!(!query_order(deps.as_ref(), 1).unwrap().is_open);
+    }
+
+    #[test]
+    fn list_orders_paginates() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        for _ in 0..5 {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+                ..Default::default()
+            };
+            let info = mock_info("maker", &coins(100, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+
+        let msg = QueryMsg::ListOrders {
+            start_after: Some(2),
+            limit: Some(2),
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let res: OrdersResponse = from_binary(&res).unwrap();
+        let ids: Vec<u64> = res.orders.iter().map(|order| order.id).collect();
+        assert_eq!(vec![3, 4], ids);
+
+        let msg = QueryMsg::ListOrders {
+            start_after: None,
+            limit: None,
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let res: OrdersResponse = from_binary(&res).unwrap();
+        assert_eq!(5, res.orders.len());
+    }
+
+    #[test]
+    fn orders_by_maker_filters_on_maker() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        for maker in ["alice", "bob", "alice", "bob", "alice"] {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+                ..Default::default()
+            };
+            let info = mock_info(maker, &coins(100, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+
+        let msg = QueryMsg::OrdersByMaker {
+            maker: String::from("alice"),
+            start_after: None,
+            limit: None,
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let res: OrdersResponse = from_binary(&res).unwrap();
+        let ids: Vec<u64> = res.orders.iter().map(|order| order.id).collect();
+        assert_eq!(vec![1, 3, 5], ids);
+
+        let msg = QueryMsg::OrdersByMaker {
+            maker: String::from("bob"),
+            start_after: Some(2),
+            limit: Some(1),
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let res: OrdersResponse = from_binary(&res).unwrap();
+        let ids: Vec<u64> = res.orders.iter().map(|order| order.id).collect();
+        assert_eq!(vec![4], ids);
+    }
+
+    #[test]
+    fn cancel_group_refunds_grouped_orders() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        for group_id in ["ladder", "ladder", "ladder", "other"] {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+                group_id: Some(String::from(group_id)),
+                ..Default::default()
+            };
+            let info = mock_info("maker", &coins(100, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+
+        // Another maker's order under the same group id
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+            group_id: Some(String::from("ladder")),
+            ..Default::default()
+        };
+        let info = mock_info("stranger", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let msg = QueryMsg::OrdersByGroup {
+            maker: String::from("maker"),
+            group_id: String::from("ladder"),
+            start_after: None,
+            limit: None,
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let res: OrdersResponse = from_binary(&res).unwrap();
+        let ids: Vec<u64> = res.orders.iter().map(|order| order.id).collect();
+        assert_eq!(vec![1, 2, 3], ids);
+
+        let msg = QueryMsg::OrdersByGroup {
+            maker: String::from("maker"),
+            group_id: String::from("ladder"),
+            start_after: Some(1),
+            limit: Some(1),
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let res: OrdersResponse = from_binary(&res).unwrap();
+        let ids: Vec<u64> = res.orders.iter().map(|order| order.id).collect();
+        assert_eq!(vec![2], ids);
+
+        // Ungrouped orders are not indexed under an empty group id
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        let msg = QueryMsg::OrdersByGroup {
+            maker: String::from("maker"),
+            group_id: String::new(),
+            start_after: None,
+            limit: None,
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let res: OrdersResponse = from_binary(&res).unwrap();
+        assert!(res.orders.is_empty());
+
+        let msg = ExecuteMsg::CancelGroup {
+            group_id: String::from("ladder"),
+        };
+        // Only the maker's own orders are cancelled
+        let info = mock_info("maker", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(3, res.messages.len());
+        for id in 1..=3 {
+            assert!(!query_order(deps.as_ref(), id).unwrap().is_open);
+        }
+        for id in 4..=6 {
+            assert!(query_order(deps.as_ref(), id).unwrap().is_open);
+        }
+    }
+
+    #[test]
+    fn cancel_order_returns_maker_token() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        // Native maker
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        // cw20 maker
+        let msg = OpenOrderMsg {
+            taker_token: GenericBalance {
+                native: coins(100, "native"),
+                cw20: vec![],
+            },
+            ..Default::default()
+        };
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(200),
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+
+        let cancel = |order_id| ExecuteMsg::CancelOrder { order_id };
+        let info = mock_info("stranger", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, cancel(1)).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let info = mock_info("maker", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), cancel(1)).unwrap();
+        assert_eq!(("method", "cancel_order"), res.attributes[0]);
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(BankMsg::Send {
+                to_address: String::from("maker"),
+                amount: coins(100, "native"),
+            })]
+        );
+        assert!(!query_order(deps.as_ref(), 1).unwrap().is_open);
+
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), cancel(2)).unwrap();
+        let transfer = Cw20ExecuteMsg::Transfer {
+            recipient: String::from("maker"),
+            amount: Uint128::new(200),
+        };
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(WasmMsg::Execute {
+                contract_addr: String::from("my-cw20-token"),
+                msg: to_binary(&transfer).unwrap(),
+                funds: vec![],
+            })]
+        );
+
+        let err = execute(deps.as_mut(), mock_env(), info, cancel(1)).unwrap_err();
+        assert!(matches!(err, ContractError::OrderClosed {}));
+    }
+
+    #[test]
+    fn decline_reserved_order() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_tokens = create_cw20_tokens(&String::from("my-cw20-token"), 12345u128.into());
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens,
+            target_address: Some(String::from("target")),
+            ..Default::default()
+        };
+        let balance = coins(100, "native");
+        let info = mock_info("maker", &balance);
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        // Only the target can decline
+        let msg = ExecuteMsg::DeclineOrder { order_id: 1 };
+        let info = mock_info("stranger", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let info = mock_info("target", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), msg.clone()).unwrap();
+        assert_eq!(("method", "decline_order"), res.attributes[0]);
+        assert_eq!(("order_id", "1"), res.attributes[1]);
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(BankMsg::Send {
+                to_address: String::from("maker"),
+                amount: balance,
+            })]
+        );
+        assert!(!query_order(deps.as_ref(), 1).unwrap().is_open);
+
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::OrderClosed {}));
+    }
+
+    #[test]
+    fn make_reserved_order_public() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_tokens = create_cw20_tokens(&String::from("my-cw20-token"), 12345u128.into());
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens,
+            target_address: Some(String::from("target")),
+            ..Default::default()
+        };
+        let balance = coins(100, "native");
+        let info = mock_info("maker", &balance);
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        // Only the maker can make it public
+        let msg = ExecuteMsg::MakePublic { order_id: 1 };
+        let info = mock_info("target", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let info = mock_info("maker", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("method", "make_public"), res.attributes[0]);
+        assert_eq!(("order_id", "1"), res.attributes[1]);
+        assert_eq!(None, query_order(deps.as_ref(), 1).unwrap().target_address);
+
+        // A third party can now close it
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("stranger"),
+            amount: Uint128::new(12345),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+        assert_eq!(2, res.messages.len());
+        assert_eq!(("method", "close_order"), res.attributes[0]);
+        assert_eq!(
+            res.messages[1],
+            SubMsg::new(BankMsg::Send {
+                to_address: String::from("stranger"),
+                amount: balance,
+            })
+        );
+    }
+
+    #[test]
+    fn transfer_order_moves_proceeds() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&cw20_token_contract, 100u128.into()),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let msg = ExecuteMsg::TransferOrder {
+            order_id: 1,
+            new_maker: String::from("new-maker"),
+        };
+        let info = mock_info("stranger", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let info = mock_info("maker", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(("method", "transfer_order"), res.attributes[0]);
+        assert_eq!(
+            Addr::unchecked("new-maker"),
+            query_order(deps.as_ref(), 1).unwrap().maker_address
+        );
+
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages[0],
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: cw20_token_contract,
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from("new-maker"),
+                    amount: Uint128::new(100),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn transfer_order_drops_previous_makers_hooks() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&cw20_token_contract, 100u128.into()),
+            maker_callback: Some(String::from("maker-hook")),
+            deposit_to_vault: Some(String::from("vault")),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let msg = ExecuteMsg::TransferOrder {
+            order_id: 1,
+            new_maker: String::from("new-maker"),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("maker", &[]), msg).unwrap();
+
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+        // Only the proceeds to the new maker and the escrow to the taker
+        assert_eq!(2, res.messages.len());
+        assert_eq!(
+            res.messages[0],
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: cw20_token_contract,
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from("new-maker"),
+                    amount: Uint128::new(100),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn update_orders_changes_demands_atomically() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        for maker in ["maker", "maker", "other-maker"] {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+                ..Default::default()
+            };
+            let info = mock_info(maker, &coins(100, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+
+        let demand =
+            |amount: u128| create_cw20_tokens(&String::from("my-cw20-token"), amount.into());
+        let msg = ExecuteMsg::UpdateOrders {
+            updates: vec![(1, demand(110)), (2, demand(120))],
+        };
+        let info = mock_info("maker", &[]);
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        let order = query_order(deps.as_ref(), 1).unwrap();
+        assert_eq!(Uint128::new(110), order.taker_token.cw20[0].amount);
+        let order = query_order(deps.as_ref(), 2).unwrap();
+        assert_eq!(Uint128::new(120), order.taker_token.cw20[0].amount);
+
+        // Order 3 belongs to another maker, so the whole batch fails
+        let msg = ExecuteMsg::UpdateOrders {
+            updates: vec![(1, demand(130)), (3, demand(130))],
+        };
+        let info = mock_info("maker", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+    }
+
+    #[test]
+    fn close_with_stale_hash_fails() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let demand =
+            |amount: u128| create_cw20_tokens(&String::from("my-cw20-token"), amount.into());
+        let msg = OpenOrderMsg {
+            taker_token: demand(100),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        let seen = query_order(deps.as_ref(), 1).unwrap().hash;
+
+        let msg = ExecuteMsg::UpdateOrders {
+            updates: vec![(1, demand(90))],
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("maker", &[]), msg).unwrap();
+        let current = query_order(deps.as_ref(), 1).unwrap().hash;
+        assert_ne!(seen, current);
+
+        let close = |expected_hash| {
+            let receive = Cw20ReceiveMsg {
+                sender: String::from("taker"),
+                amount: Uint128::new(90),
+                msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                    order_id: 1,
+                    expected_hash: Some(expected_hash),
+                    ..Default::default()
+                }))
+                .unwrap(),
+            };
+            ExecuteMsg::Receive(receive)
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), close(seen)).unwrap_err();
+        assert!(matches!(err, ContractError::StaleOrder {}));
+
+        execute(deps.as_mut(), mock_env(), info, close(current)).unwrap();
+    }
+
+    #[test]
+    fn withdraw_fraction_keeps_price() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 200u128.into()),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let msg = ExecuteMsg::WithdrawFraction {
+            order_id: 1,
+            bps: 2_500,
+        };
+        let info = mock_info("stranger", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let info = mock_info("maker", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(BankMsg::Send {
+                to_address: String::from("maker"),
+                amount: coins(25, "native"),
+            })]
+        );
+
+        let order = query_order(deps.as_ref(), 1).unwrap();
+        assert_eq!(coins(75, "native"), order.maker_token.native);
+        assert_eq!(Uint128::new(150), order.taker_token.cw20[0].amount);
+
+        // Withdrawing everything would leave an empty order
+        for bps in [10_000, 20_000] {
+            let msg = ExecuteMsg::WithdrawFraction { order_id: 1, bps };
+            let info = mock_info("maker", &[]);
+            let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+            assert!(matches!(err, ContractError::InvalidWithdrawal {}));
+        }
+    }
+
+    #[test]
+    fn split_order_preserves_escrow() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_tokens = create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into());
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens,
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let msg = ExecuteMsg::SplitOrder {
+            order_id: 1,
+            first_fraction_bps: 4_000,
+        };
+        let info = mock_info("stranger", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let info = mock_info("maker", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("method", "split_order"), res.attributes[0]);
+        assert_eq!(("new_order_id", "2"), res.attributes[2]);
+        assert_eq!(
+            vec![Event::new("order_opened").add_attribute("order_id", "2")],
+            res.events
+        );
+
+        let first = query_order(deps.as_ref(), 1).unwrap();
+        assert_eq!(coins(40, "native"), first.maker_token.native);
+        assert_eq!(Uint128::new(40), first.taker_token.cw20[0].amount);
+        let second = query_order(deps.as_ref(), 2).unwrap();
+        assert_eq!(coins(60, "native"), second.maker_token.native);
+        assert_eq!(Uint128::new(60), second.taker_token.cw20[0].amount);
+        assert!(second.is_open);
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::OrderMeta { id: 2 }).unwrap();
+        let meta: OrderMetaResponse = from_binary(&res).unwrap();
+        assert_eq!(mock_env().block.height, meta.block_height);
+
+        // A split leaving nothing in one of the orders is rejected
+        let msg = ExecuteMsg::SplitOrder {
+            order_id: 1,
+            first_fraction_bps: 10_000,
+        };
+        let info = mock_info("maker", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidSplit {}));
+
+        // As is a fraction above the whole order
+        let msg = ExecuteMsg::SplitOrder {
+            order_id: 1,
+            first_fraction_bps: 20_000,
+        };
+        let info = mock_info("maker", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidSplit {}));
+    }
+
+    #[test]
+    fn open_order_within_cooldown_fails() {
+        let mut deps = mock_deps();
+        let msg = InstantiateMsg {
+            open_cooldown_blocks: Some(10),
+            denom_aliases: None,
+            asset_decimals: None,
+        };
+        instantiate(deps.as_mut(), mock_env(), mock_info("anyone", &[]), msg).unwrap();
+
+        let cw20_tokens = create_cw20_tokens(&String::from("my-cw20-token"), 12345u128.into());
+        let msg = ExecuteMsg::OpenOrder(OpenOrderMsg {
+            taker_token: cw20_tokens,
+            target_address: None,
+            ..Default::default()
+        });
+        let info = mock_info("maker", &coins(100, "native"));
+        let mut env = mock_env();
+        execute(deps.as_mut(), env.clone(), info.clone(), msg.clone()).unwrap();
+
+        // Another maker is not affected by the cooldown
+        let other_info = mock_info("other", &coins(100, "native"));
+        execute(deps.as_mut(), env.clone(), other_info, msg.clone()).unwrap();
+
+        env.block.height += 9;
+        let err = execute(deps.as_mut(), env.clone(), info.clone(), msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Cooldown {}));
+
+        env.block.height += 1;
+        execute(deps.as_mut(), env.clone(), info.clone(), msg.clone()).unwrap();
+
+        // A cooldown too long to add to the height holds the maker off instead of panicking
+        let mut config = CONFIG.load(&deps.storage).unwrap();
+        config.open_cooldown_blocks = Some(u64::MAX);
+        CONFIG.save(&mut deps.storage, &config).unwrap();
+        env.block.height += 1;
+        let err = execute(deps.as_mut(), env, info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::Cooldown {}));
+    }
+
+    #[test]
+    fn close_order_emits_exec_price() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, Uint128::new(100));
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens,
+            target_address: None,
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(200, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        let msg = ExecuteMsg::Receive(receive);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(("exec_price", "0.5"), res.attributes[4]);
+    }
+
+    #[test]
+    fn exec_price_adjusts_for_decimals() {
+        let mut deps = mock_deps();
+        let msg = InstantiateMsg {
+            open_cooldown_blocks: None,
+            denom_aliases: None,
+            asset_decimals: Some(vec![
+                (String::from("native"), 6),
+                (String::from("my-cw20-token"), 9),
+            ]),
+        };
+        instantiate(deps.as_mut(), mock_env(), mock_info("anyone", &[]), msg).unwrap();
+
+        // 2 native for 1 cw20 token, which would be 500 from the raw amounts
+        let cw20_token_contract = String::from("my-cw20-token");
+        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, Uint128::new(1_000_000_000));
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens,
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(2_000_000, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(1_000_000_000),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        let msg = ExecuteMsg::Receive(receive);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(("exec_price", "0.5"), res.attributes[4]);
+    }
+
+    #[test]
+    fn receive_from_spoofed_cw20_fails() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let mut native_tokens = GenericBalance::default();
+        native_tokens.add_tokens(Balance::Native(NativeBalance(coins(100, "native"))));
+        let msg = OpenOrderMsg {
+            taker_token: native_tokens,
+            target_address: None,
+            ..Default::default()
+        };
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(12345),
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("spoofer", &[]);
+        let msg = ExecuteMsg::Receive(receive);
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidCw20 {}));
+    }
+
+    #[test]
+    fn update_config_only_changes_provided_fields() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let msg = ExecuteMsg::UpdateConfig {
+            owner: None,
+            open_cooldown_blocks: Some(5),
+            clear_open_cooldown: false,
+            denom_aliases: None,
+            asset_decimals: None,
+            blacklist: None,
+            oracle: None,
+            clear_oracle: false,
+            min_prices: None,
+        };
+        let info = mock_info("stranger", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let info = mock_info("anyone", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(("method", "update_config"), res.attributes[0]);
+
+        let config = CONFIG.load(&deps.storage).unwrap();
+        assert_eq!(Addr::unchecked("anyone"), config.owner);
+        assert_eq!(Some(5), config.open_cooldown_blocks);
+
+        // Leaving a field out keeps it, so clearing takes an explicit flag
+        let msg = ExecuteMsg::UpdateConfig {
+            owner: None,
+            open_cooldown_blocks: Some(5),
+            clear_open_cooldown: true,
+            denom_aliases: None,
+            asset_decimals: None,
+            blacklist: None,
+            oracle: None,
+            clear_oracle: false,
+            min_prices: None,
+        };
+        let info = mock_info("anyone", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::ConflictingConfigUpdate {}));
+
+        let msg = ExecuteMsg::UpdateConfig {
+            owner: None,
+            open_cooldown_blocks: None,
+            clear_open_cooldown: false,
+            denom_aliases: None,
+            asset_decimals: None,
+            blacklist: None,
+            oracle: Some(String::from("oracle")),
+            clear_oracle: false,
+            min_prices: None,
+        };
+        let info = mock_info("anyone", &[]);
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert!(CONFIG.load(&deps.storage).unwrap().oracle.is_some());
+
+        let msg = ExecuteMsg::UpdateConfig {
+            owner: None,
+            open_cooldown_blocks: None,
+            clear_open_cooldown: true,
+            denom_aliases: None,
+            asset_decimals: None,
+            blacklist: None,
+            oracle: None,
+            clear_oracle: true,
+            min_prices: None,
+        };
+        let info = mock_info("anyone", &[]);
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        let config = CONFIG.load(&deps.storage).unwrap();
+        assert_eq!(None, config.open_cooldown_blocks);
+        assert_eq!(None, config.oracle);
+    }
+
+    #[test]
+    fn remap_cw20_rewrites_order_tokens() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        // One order offers the token and another demands it
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("abc-token"), 100u128.into()),
+            ..Default::default()
+        };
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+
+        // A taker has part of the first order's demand deposited
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(40),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+
+        let msg = ExecuteMsg::RemapCw20 {
+            old_contract: String::from("my-cw20-token"),
+            new_contract: String::from("my-cw20-token"),
+        };
+        let info = mock_info("anyone", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::SameCw20Contract {}));
+
+        let msg = ExecuteMsg::RemapCw20 {
+            old_contract: String::from("my-cw20-token"),
+            new_contract: String::from("xyz-token"),
+        };
+        let info = mock_info("stranger", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let info = mock_info("anyone", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(("orders_remapped", "2"), res.attributes[1]);
+        assert_eq!(("deposits_remapped", "1"), res.attributes[2]);
+
+        // The deposit counts toward the order under the new contract
+        let taker = Addr::unchecked("taker");
+        let old_key = (1u64.into(), &taker, &Addr::unchecked("my-cw20-token"));
+        assert!(PENDING_DEPOSITS
+            .may_load(&deps.storage, old_key)
+            .unwrap()
+            .is_none());
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(60),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info("xyz-token", &[]);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+        assert_eq!("order_closed", res.events[0].ty);
+
+        let order = query_order(deps.as_ref(), 1).unwrap();
+        assert_eq!(
+            Addr::unchecked("xyz-token"),
+            order.taker_token.cw20[0].address
+        );
+        let order = query_order(deps.as_ref(), 2).unwrap();
+        assert_eq!(
+            Addr::unchecked("xyz-token"),
+            order.maker_token.cw20[0].address
+        );
+        assert_eq!(
+            Addr::unchecked("abc-token"),
+            order.taker_token.cw20[0].address
+        );
+    }
+
+    #[test]
+    fn remap_cw20_rewrites_allowance_remainder() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("abc-token"), 100u128.into()),
+            remainder_from_allowance: Some(Cw20Coin {
+                address: String::from("my-cw20-token"),
+                amount: Uint128::new(40),
+            }),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(10, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let msg = ExecuteMsg::RemapCw20 {
+            old_contract: String::from("my-cw20-token"),
+            new_contract: String::from("xyz-token"),
+        };
+        let info = mock_info("anyone", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(("orders_remapped", "1"), res.attributes[1]);
+
+        // The close pulls the remainder from the new contract
+        let order = orders().load(&deps.storage, 1u64.into()).unwrap();
+        assert_eq!(
+            Some(Cw20CoinVerified {
+                address: Addr::unchecked("xyz-token"),
+                amount: Uint128::new(40),
+            }),
+            order.remainder_from_allowance
+        );
+    }
+
+    #[test]
+    fn remap_cw20_moves_stranded_tokens() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("abc-token"), 100u128.into()),
+            ..Default::default()
+        };
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+        let info = mock_info("anyone", &[]);
+        let msg = ExecuteMsg::ForceCloseBroken { order_id: 1 };
+        execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+
+        let msg = ExecuteMsg::RemapCw20 {
+            old_contract: String::from("my-cw20-token"),
+            new_contract: String::from("xyz-token"),
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let info = mock_info("maker", &[]);
+        let msg = ExecuteMsg::ClaimStranded {
+            cw20_address: String::from("my-cw20-token"),
+        };
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap_err();
+        assert!(matches!(err, ContractError::NothingStranded {}));
+
+        let msg = ExecuteMsg::ClaimStranded {
+            cw20_address: String::from("xyz-token"),
+        };
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        let send_msg = Cw20ExecuteMsg::Transfer {
+            recipient: String::from("maker"),
+            amount: Uint128::new(100),
+        };
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(WasmMsg::Execute {
+                contract_addr: String::from("xyz-token"),
+                msg: to_binary(&send_msg).unwrap(),
+                funds: vec![]
+            })]
+        );
+    }
+
+    #[test]
+    fn force_close_broken_skips_cw20_transfer() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("abc-token"), 100u128.into()),
+            ..Default::default()
+        };
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+
+        let msg = ExecuteMsg::ForceCloseBroken { order_id: 1 };
+        let info = mock_info("maker", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        // A taker's partial deposit is returned with the close
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(40),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info("abc-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+
+        let info = mock_info("anyone", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), msg.clone()).unwrap();
+        assert_eq!(("stranded", "my-cw20-token:100"), res.attributes[2]);
+        let send_msg = Cw20ExecuteMsg::Transfer {
+            recipient: String::from("taker"),
+            amount: Uint128::new(40),
+        };
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(WasmMsg::Execute {
+                contract_addr: String::from("abc-token"),
+                msg: to_binary(&send_msg).unwrap(),
+                funds: vec![]
+            })]
+        );
+        assert!(!query_order(deps.as_ref(), 1).unwrap().is_open);
+
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::OrderClosed {}));
+
+        // The maker claims the cw20 once it can be transferred again
+        let msg = ExecuteMsg::ClaimStranded {
+            cw20_address: String::from("my-cw20-token"),
+        };
+        let info = mock_info("stranger", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::NothingStranded {}));
+
+        let info = mock_info("maker", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), msg.clone()).unwrap();
+        let send_msg = Cw20ExecuteMsg::Transfer {
+            recipient: String::from("maker"),
+            amount: Uint128::new(100),
+        };
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(WasmMsg::Execute {
+                contract_addr: String::from("my-cw20-token"),
+                msg: to_binary(&send_msg).unwrap(),
+                funds: vec![]
+            })]
+        );
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::NothingStranded {}));
+    }
+
+    #[test]
+    fn gift_order_claimed_by_target() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        // Gifts must be reserved for a target
+        let msg = OpenOrderMsg {
+            is_gift: true,
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        let err = execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap_err();
+        assert_eq!(
+            ContractError::OrderInvalidReason(OrderInvalidReason::InvalidGift).to_string(),
+            err.to_string()
+        );
+
+        let msg = OpenOrderMsg {
+            target_address: Some(String::from("target")),
+            is_gift: true,
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let msg = ExecuteMsg::CloseOrder(CloseOrderMsg {
+            order_id: 1,
+            ..Default::default()
+        });
+        let info = mock_info("stranger", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::OrderReserved {}));
+
+        let info = mock_info("target", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(
+            res.messages,
+            vec![SubMsg::new(BankMsg::Send {
+                to_address: String::from("target"),
+                amount: coins(100, "native"),
+            })]
+        );
+    }
+
+    #[test]
+    fn open_order_below_min_price_fails() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let msg = ExecuteMsg::UpdateConfig {
+            owner: None,
+            open_cooldown_blocks: None,
+            clear_open_cooldown: false,
+            denom_aliases: None,
+            asset_decimals: None,
+            blacklist: None,
+            oracle: None,
+            clear_oracle: false,
+            min_prices: Some(vec![(
+                String::from("native"),
+                String::from("my-cw20-token"),
+                Decimal::percent(200),
+            )]),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), msg).unwrap();
+
+        let open = |taker_amount: u128| {
+            ExecuteMsg::OpenOrder(OpenOrderMsg {
+                taker_token: create_cw20_tokens(
+                    &String::from("my-cw20-token"),
+                    taker_amount.into(),
+                ),
+                ..Default::default()
+            })
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), open(199)).unwrap_err();
+        assert!(matches!(
+            err,
+            ContractError::OrderInvalidReason(OrderInvalidReason::BelowMinPrice)
+        ));
+
+        // Exactly on the floor
+        execute(deps.as_mut(), mock_env(), info, open(200)).unwrap();
+    }
+
+    #[test]
+    fn blacklisted_taker_cannot_close() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let msg = ExecuteMsg::UpdateConfig {
+            owner: None,
+            open_cooldown_blocks: None,
+            clear_open_cooldown: false,
+            denom_aliases: None,
+            asset_decimals: None,
+            blacklist: Some(vec![String::from("bad-taker")]),
+            oracle: None,
+            clear_oracle: false,
+            min_prices: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), msg).unwrap();
+        let res = query_is_blacklisted(deps.as_ref(), String::from("bad-taker")).unwrap();
+        assert!(res.blacklisted);
+        let res = query_is_blacklisted(deps.as_ref(), String::from("taker")).unwrap();
+        assert!(!res.blacklisted);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&cw20_token_contract, Uint128::new(100)),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        for (taker, allowed) in [("bad-taker", false), ("taker", true)] {
+            let receive = Cw20ReceiveMsg {
+                sender: String::from(taker),
+                amount: Uint128::new(100),
+                msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                    order_id: 1,
+                    ..Default::default()
+                }))
+                .unwrap(),
+            };
+            let info = mock_info(&cw20_token_contract, &[]);
+            let res = execute(
+                deps.as_mut(),
+                mock_env(),
+                info,
+                ExecuteMsg::Receive(receive),
+            );
+            if allowed {
+                assert_eq!(("method", "close_order"), res.unwrap().attributes[0]);
+            } else {
+                assert!(matches!(res.unwrap_err(), ContractError::Unauthorized {}));
+            }
+        }
+    }
+
+    #[test]
+    fn close_order_requires_taker_ack() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let mut native_tokens = GenericBalance::default();
+        native_tokens.add_tokens(Balance::Native(NativeBalance(coins(100, "native"))));
+        let msg = OpenOrderMsg {
+            taker_token: native_tokens,
+            require_taker_ack: Some(String::from("nonce-123")),
+            ..Default::default()
+        };
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(12345),
+            msg: to_binary(&ReceiveMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+
+        let info = mock_info("taker", &coins(100, "native"));
+        for ack in [None, Some(String::from("wrong"))] {
+            let msg = ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ack,
+                ..Default::default()
+            });
+            let err = execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap_err();
+            assert!(matches!(err, ContractError::AckMismatch {}));
+        }
+
+        let msg = ExecuteMsg::CloseOrder(CloseOrderMsg {
+            order_id: 1,
+            ack: Some(String::from("nonce-123")),
+            ..Default::default()
+        });
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(("method", "close_order"), res.attributes[0]);
+    }
+
+    #[test]
+    fn query_order_through_entry_point() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::Order { id: 1 }).unwrap();
+        let order: OrderResponse = from_binary(&res).unwrap();
+        assert_eq!(1, order.id);
+        assert_eq!(Addr::unchecked("maker"), order.maker_address);
+
+        let err = query(deps.as_ref(), mock_env(), QueryMsg::Order { id: 2 }).unwrap_err();
+        assert_eq!(StdError::generic_err("Order 2 does not exist"), err);
+    }
+
+    #[test]
+    fn close_order_matches_native_coins_in_any_order() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let msg = OpenOrderMsg {
+            taker_token: GenericBalance {
+                native: vec![coin(50, "uusd"), coin(20, "native")],
+                cw20: vec![],
+            },
+            ..Default::default()
+        };
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+
+        let msg = ExecuteMsg::CloseOrder(CloseOrderMsg {
+            order_id: 1,
+            ..Default::default()
+        });
+        let info = mock_info("taker", &[coin(20, "native"), coin(50, "uusd")]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(
+            res.messages[0],
+            SubMsg::new(BankMsg::Send {
+                to_address: String::from("maker"),
+                amount: vec![coin(20, "native"), coin(50, "uusd")],
+            })
+        );
+        assert!(!query_order(deps.as_ref(), 1).unwrap().is_open);
+    }
+
+    #[test]
+    fn close_order_with_two_native_denoms() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        // The uatom demand is split over two entries
+        let msg = OpenOrderMsg {
+            taker_token: GenericBalance {
+                native: vec![coin(60, "uatom"), coin(50, "uosmo"), coin(40, "uatom")],
+                cw20: vec![],
+            },
+            ..Default::default()
+        };
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+        assert_eq!(
+            vec![coin(100, "uatom"), coin(50, "uosmo")],
+            query_order(deps.as_ref(), 1).unwrap().taker_token.native
+        );
+
+        let msg = ExecuteMsg::CloseOrder(CloseOrderMsg {
+            order_id: 1,
+            ..Default::default()
+        });
+        let info = mock_info("taker", &[coin(100, "uatom")]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::OrderUnmatched {}));
+
+        let info = mock_info("taker", &[coin(100, "uatom"), coin(50, "uosmo")]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(
+            res.messages[0],
+            SubMsg::new(BankMsg::Send {
+                to_address: String::from("maker"),
+                amount: vec![coin(100, "uatom"), coin(50, "uosmo")],
+            })
+        );
+    }
+
+    #[test]
+    fn query_close_funds_of_native_and_cw20_demands() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        // Native maker, cw20 taker
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        // cw20 maker, native taker
+        let msg = OpenOrderMsg {
+            taker_token: GenericBalance {
+                native: coins(250, "native"),
+                cw20: vec![],
+            },
+            ..Default::default()
+        };
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::CloseFunds { order_id: 1 },
+        )
+        .unwrap();
+        let res: CloseFundsResponse = from_binary(&res).unwrap();
+        assert_eq!(
+            CloseFundsResponse {
+                funds: vec![],
+                cw20: true,
+            },
+            res
+        );
+
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::CloseFunds { order_id: 2 },
+        )
+        .unwrap();
+        let res: CloseFundsResponse = from_binary(&res).unwrap();
+        assert_eq!(
+            CloseFundsResponse {
+                funds: coins(250, "native"),
+                cw20: false,
+            },
+            res
+        );
+    }
+
+    #[test]
+    fn query_asset_kind_of_taker_token() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        // Native maker, cw20 taker
+        let cw20_tokens = create_cw20_tokens(&String::from("my-cw20-token"), 12345u128.into());
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens,
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        // cw20 maker, native taker
+        let mut native_tokens = GenericBalance::default();
+        native_tokens.add_tokens(Balance::Native(NativeBalance(coins(100, "native"))));
+        let msg = OpenOrderMsg {
+            taker_token: native_tokens,
+            ..Default::default()
+        };
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(12345),
+            msg: to_binary(&ReceiveMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+
+        assert_eq!(
+            AssetKindResponse::Cw20 {
+                contract: Addr::unchecked("my-cw20-token")
+            },
+            query_asset_kind(deps.as_ref(), 1).unwrap()
+        );
+        assert_eq!(
+            AssetKindResponse::Native {
+                denoms: vec![String::from("native")]
+            },
+            query_asset_kind(deps.as_ref(), 2).unwrap()
+        );
+    }
+
+    #[test]
+    fn open_order_with_unexpected_maker_token_fails() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let mut expected = GenericBalance::default();
+        expected.add_tokens(Balance::Native(NativeBalance(coins(100, "native"))));
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 12345u128.into()),
+            expected_maker_token: Some(expected),
+            ..Default::default()
+        };
+
+        let info = mock_info("maker", &coins(1000, "native"));
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::OpenOrder(msg.clone()),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            ContractError::OrderInvalidReason(OrderInvalidReason::MakerTokenMismatch)
+        ));
+
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        // The tokens may be listed in any order
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 12345u128.into()),
+            expected_maker_token: Some(GenericBalance {
+                native: vec![coin(200, "other"), coin(100, "native")],
+                cw20: vec![],
+            }),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &[coin(100, "native"), coin(200, "other")]);
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+    }
+
+    #[test]
+    fn query_best_order_returns_cheapest() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        for (maker_amount, taker_amount) in [(100, 300), (100, 150), (100, 200)] {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&cw20_token_contract, Uint128::new(taker_amount)),
+                ..Default::default()
+            };
+            let info = mock_info("maker", &coins(maker_amount, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+        // An order for another pair is ignored
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&cw20_token_contract, Uint128::new(1)),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "other"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let res = query_best_order(
+            deps.as_ref(),
+            &mock_env(),
+            String::from("native"),
+            cw20_token_contract.clone(),
+            None,
+        )
+        .unwrap();
+        let order = res.order.unwrap();
+        assert_eq!(2, order.id);
+        assert_eq!(coins(100, "native"), order.maker_token.native);
+
+        let res = query_best_order(
+            deps.as_ref(),
+            &mock_env(),
+            cw20_token_contract,
+            String::from("native"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(None, res.order);
+    }
+
+    #[test]
+    fn query_twap_weights_prices_by_time() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        for amount in [200u128, 400] {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&cw20_token_contract, amount.into()),
+                ..Default::default()
+            };
+            let info = mock_info("maker", &coins(100, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+
+        // Close order 1 at a price of 2, then order 2 at a price of 4 after 100 seconds
+        let mut env = mock_env();
+        for (order_id, amount) in [(1, 200u128), (2, 400)] {
+            let receive = Cw20ReceiveMsg {
+                sender: String::from("taker"),
+                amount: amount.into(),
+                msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                    order_id,
+                    ..Default::default()
+                }))
+                .unwrap(),
+            };
+            let info = mock_info(&cw20_token_contract, &[]);
+            let msg = ExecuteMsg::Receive(receive);
+            execute(deps.as_mut(), env.clone(), info, msg).unwrap();
+            env.block.time = env.block.time.plus_seconds(100);
+        }
+
+        let base = String::from("native");
+        let res = query_twap(
+            deps.as_ref(),
+            env.clone(),
+            base.clone(),
+            cw20_token_contract.clone(),
+            200,
+        )
+        .unwrap();
+        assert_eq!(Decimal::from_ratio(3u128, 1u128), res.price);
+        let res = query_twap(
+            deps.as_ref(),
+            env.clone(),
+            base.clone(),
+            cw20_token_contract.clone(),
+            50,
+        )
+        .unwrap();
+        assert_eq!(Decimal::from_ratio(4u128, 1u128), res.price);
+
+        // The window cannot reach back before the first close
+        query_twap(deps.as_ref(), env, base, cw20_token_contract, 300).unwrap_err();
+    }
+
+    #[test]
+    fn extreme_price_does_not_block_later_closes() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("xyz-token");
+        for amount in [1_000_000_000_000_000_000u128, 5, 5] {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&cw20_token_contract, amount.into()),
+                ..Default::default()
+            };
+            let info = mock_info("maker", &coins(1, "uatom"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+
+        let mut env = mock_env();
+        for (order_id, amount) in [(1, 1_000_000_000_000_000_000u128), (2, 5), (3, 5)] {
+            let receive = Cw20ReceiveMsg {
+                sender: String::from("taker"),
+                amount: amount.into(),
+                msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                    order_id,
+                    ..Default::default()
+                }))
+                .unwrap(),
+            };
+            let info = mock_info(&cw20_token_contract, &[]);
+            let msg = ExecuteMsg::Receive(receive);
+            execute(deps.as_mut(), env.clone(), info, msg).unwrap();
+            env.block.time = env.block.time.plus_seconds(1000);
+        }
+
+        // The history restarted at the first normal close
+        let res = query_twap(
+            deps.as_ref(),
+            env,
+            String::from("uatom"),
+            cw20_token_contract,
+            1000,
+        )
+        .unwrap();
+        assert_eq!(Decimal::from_ratio(5u128, 1u128), res.price);
+    }
+
+    #[test]
+    fn close_order_calls_back_maker() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let mut native_tokens = GenericBalance::default();
+        native_tokens.add_tokens(Balance::Native(NativeBalance(coins(100, "native"))));
+        let msg = OpenOrderMsg {
+            taker_token: native_tokens.clone(),
+            maker_callback: Some(String::from("maker-hook")),
+            ..Default::default()
+        };
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(12345),
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+
+        let msg = ExecuteMsg::CloseOrder(CloseOrderMsg {
+            order_id: 1,
+            ..Default::default()
+        });
+        let info = mock_info("taker", &coins(100, "native"));
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(3, res.messages.len());
+        let callback = MakerCallbackMsg::OrderFilled {
+            order_id: 1,
+            taker_address: Addr::unchecked("taker"),
+            taker_token: native_tokens,
+        };
+        assert_eq!(
+            res.messages[2],
+            SubMsg::reply_on_error(
+                WasmMsg::Execute {
+                    contract_addr: String::from("maker-hook"),
+                    msg: to_binary(&callback).unwrap(),
+                    funds: vec![],
+                },
+                MAKER_CALLBACK_REPLY_ID,
+            )
+        );
+    }
+
+    #[test]
+    fn search_orders_by_maker_and_taker_denom() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        for (maker, denom) in [
+            ("maker1", "my-cw20-token"),
+            ("maker1", "xyz-token"),
+            ("maker2", "my-cw20-token"),
+        ] {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from(denom), 100u128.into()),
+                ..Default::default()
+            };
+            let info = mock_info(maker, &coins(100, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+
+        let res = query_search(
+            deps.as_ref(),
+            &mock_env(),
+            Some(String::from("maker1")),
+            Some(String::from("my-cw20-token")),
+            Some(true),
+            Some(Uint128::new(100)),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let ids: Vec<u64> = res.orders.iter().map(|order| order.id).collect();
+        assert_eq!(vec![1], ids);
+
+        // Filters left out match everything, paginated by start_after
+        let res = query_search(
+            deps.as_ref(),
+            &mock_env(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(1),
+            Some(1),
+        )
+        .unwrap();
+        let ids: Vec<u64> = res.orders.iter().map(|order| order.id).collect();
+        assert_eq!(vec![2], ids);
+        assert_eq!(Some(2), res.next_start_after);
+
+        // The last page has no cursor
+        let res = query_search(
+            deps.as_ref(),
+            &mock_env(),
+            Some(String::from("maker2")),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let ids: Vec<u64> = res.orders.iter().map(|order| order.id).collect();
+        assert_eq!(vec![3], ids);
+        assert_eq!(None, res.next_start_after);
+    }
+
+    #[test]
+    fn search_stops_after_bounded_scan() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let total = MAX_SCAN as u64 + 5;
+        for _ in 0..total {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+                ..Default::default()
+            };
+            let info = mock_info("maker1", &coins(100, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+
+        // A filter nothing matches stops at the scan bound and hands back a cursor
+        let search = |start_after| {
+            query_search(
+                deps.as_ref(),
+                &mock_env(),
+                Some(String::from("maker2")),
+                None,
+                None,
+                None,
+                None,
+                start_after,
+                None,
+            )
+            .unwrap()
+        };
+        let res = search(None);
+        assert!(res.orders.is_empty());
+        assert_eq!(Some(MAX_SCAN as u64), res.next_start_after);
+
+        let res = search(res.next_start_after);
+        assert!(res.orders.is_empty());
+        assert_eq!(None, res.next_start_after);
+    }
+
+    #[test]
+    fn book_wide_queries_page_through_bounded_scans() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let total = MAX_SCAN as u64 + 5;
+        for _ in 0..total {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+                ..Default::default()
+            };
+            let info = mock_info("maker", &coins(100, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+        let cursor = Some(MAX_SCAN as u64);
+
+        let balance = create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into());
+        let res = query_fillable_count(deps.as_ref(), &mock_env(), balance.clone(), None).unwrap();
+        assert_eq!((MAX_SCAN as u64, cursor), (res.count, res.next_start_after));
+        let res = query_fillable_count(deps.as_ref(), &mock_env(), balance, cursor).unwrap();
+        assert_eq!((5, None), (res.count, res.next_start_after));
+
+        let res = query_stats(deps.as_ref(), &mock_env(), None).unwrap();
+        assert_eq!(
+            (MAX_SCAN as u64, cursor),
+            (res.open_orders, res.next_start_after)
+        );
+        let res = query_stats(deps.as_ref(), &mock_env(), cursor).unwrap();
+        assert_eq!((5, None), (res.open_orders, res.next_start_after));
+
+        let res = query_active_assets(deps.as_ref(), &mock_env(), None).unwrap();
+        assert_eq!(cursor, res.next_start_after);
+        assert_eq!(vec![String::from("native")], res.native_denoms);
+
+        let depth = |start_after| {
+            query_depth(
+                deps.as_ref(),
+                &mock_env(),
+                String::from("native"),
+                String::from("my-cw20-token"),
+                1,
+                start_after,
+            )
+            .unwrap()
+        };
+        let res = depth(None);
+        assert_eq!(cursor, res.next_start_after);
+        assert_eq!(
+            Uint128::new(100 * MAX_SCAN as u128),
+            res.levels[0].maker_amount
+        );
+        let res = depth(cursor);
+        assert_eq!(None, res.next_start_after);
+        assert_eq!(Uint128::new(500), res.levels[0].maker_amount);
+
+        let res = query_best_order(
+            deps.as_ref(),
+            &mock_env(),
+            String::from("native"),
+            String::from("my-cw20-token"),
+            cursor,
+        )
+        .unwrap();
+        assert_eq!(MAX_SCAN as u64 + 1, res.order.unwrap().id);
+        assert_eq!(None, res.next_start_after);
+
+        let res = query_orders_with_invalid_target(deps.as_ref(), None, None).unwrap();
+        assert!(res.orders.is_empty());
+        assert_eq!(cursor, res.next_start_after);
+    }
+
+    #[test]
+    fn close_order_with_aliased_cw20() {
+        let mut deps = mock_deps();
+        let msg = InstantiateMsg {
+            open_cooldown_blocks: None,
+            denom_aliases: Some(vec![(
+                AssetRef::Native(String::from("x")),
+                AssetRef::Cw20(Addr::unchecked("xyz-token")),
+            )]),
+            asset_decimals: None,
+        };
+        instantiate(deps.as_mut(), mock_env(), mock_info("anyone", &[]), msg).unwrap();
+
+        let mut native_tokens = GenericBalance::default();
+        native_tokens.add_tokens(Balance::Native(NativeBalance(coins(100, "x"))));
+        let msg = OpenOrderMsg {
+            taker_token: native_tokens,
+            ..Default::default()
+        };
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(50),
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+
+        // Paying with the cw20 form of "x" settles the order
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info("xyz-token", &[]);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+        assert_eq!(("method", "close_order"), res.attributes[0]);
+        assert_eq!(
+            res.messages[0],
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: String::from("xyz-token"),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: String::from("maker"),
+                    amount: Uint128::new(100),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn query_stats_aggregates_orders_and_volume() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        for _ in 0..3 {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+                ..Default::default()
+            };
+            let info = mock_info("maker", &coins(100, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+        for order_id in [1, 3] {
+            let receive = Cw20ReceiveMsg {
+                sender: String::from("taker"),
+                amount: Uint128::new(100),
+                msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                    order_id,
+                    ..Default::default()
+                }))
+                .unwrap(),
+            };
+            let info = mock_info("my-cw20-token", &[]);
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                info,
+                ExecuteMsg::Receive(receive),
+            )
+            .unwrap();
+        }
+
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::Stats { start_after: None },
+        )
+        .unwrap();
+        let stats: StatsResponse = from_binary(&res).unwrap();
+        assert_eq!(
+            query_raw_order_count(deps.as_ref()).unwrap().count,
+            stats.total_orders
+        );
+        assert_eq!(1, stats.open_orders);
+        assert_eq!(2, stats.closed_orders);
+        assert_eq!(
+            vec![
+                (String::from("my-cw20-token"), Uint128::new(200)),
+                (String::from("native"), Uint128::new(200)),
+            ],
+            stats.volume
+        );
+        let active = query_active_assets(deps.as_ref(), &mock_env(), None).unwrap();
+        assert_eq!(
+            (active.native_denoms.len() + active.cw20_contracts.len()) as u64,
+            stats.active_assets
+        );
+        assert_eq!(2, stats.active_assets);
+    }
+
+    #[test]
+    fn query_capabilities_reflects_config() {
+        let mut deps = mock_deps();
+        let msg = InstantiateMsg {
+            open_cooldown_blocks: Some(10),
+            denom_aliases: Some(vec![(
+                AssetRef::Native(String::from("native")),
+                AssetRef::Cw20(Addr::unchecked("my-cw20-token")),
+            )]),
+            asset_decimals: None,
+        };
+        let info = mock_info("anyone", &[]);
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::Capabilities {}).unwrap();
+        let capabilities: CapabilitiesResponse = from_binary(&res).unwrap();
+        assert_eq!(
+            CapabilitiesResponse {
+                partial_fills: false,
+                nft_support: false,
+                fees: false,
+                pause: false,
+                open_cooldown: true,
+                denom_aliases: true,
+                blacklist: false,
+                usd_pricing: false,
+                min_prices: false,
+            },
+            capabilities
+        );
+    }
+
+    #[test]
+    fn query_orders_with_invalid_target_finds_legacy_targets() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        for _ in 0..2 {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+                target_address: Some(String::from("taker")),
+                ..Default::default()
+            };
+            let info = mock_info("maker", &coins(100, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+        // Stand in for an order stored before targets were validated
+        let mut legacy = orders().load(&deps.storage, 2u64.into()).unwrap();
+        legacy.target_address = Some(Addr::unchecked("x"));
+        orders()
+            .save(&mut deps.storage, 2u64.into(), &legacy)
+            .unwrap();
+
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::OrdersWithInvalidTarget {
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+        let res: SearchResponse = from_binary(&res).unwrap();
+        let ids: Vec<u64> = res.orders.iter().map(|order| order.id).collect();
+        assert_eq!(vec![2], ids);
+        assert_eq!(None, res.next_start_after);
+    }
+
+    #[test]
+    fn query_raw_order_count_tracks_opens() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+        assert_eq!(0, query_raw_order_count(deps.as_ref()).unwrap().count);
+
+        for _ in 0..3 {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+                ..Default::default()
+            };
+            let info = mock_info("maker", &coins(100, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+        assert_eq!(3, query_raw_order_count(deps.as_ref()).unwrap().count);
+    }
+
+    #[test]
+    fn refund_overpayment_per_order() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        for refund_overpayment in [true, false] {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&cw20_token_contract, 100u128.into()),
+                refund_overpayment,
+                ..Default::default()
+            };
+            let info = mock_info("maker", &coins(100, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+
+        let close = |order_id| {
+            let receive = Cw20ReceiveMsg {
+                sender: String::from("taker"),
+                amount: Uint128::new(120),
+                msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                    order_id,
+                    ..Default::default()
+                }))
+                .unwrap(),
+            };
+            ExecuteMsg::Receive(receive)
+        };
+
+        // Order 1 opted in and refunds the surplus
+        let info = mock_info(&cw20_token_contract, &[]);
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), close(1)).unwrap();
+        assert_eq!(3, res.messages.len());
+        let refund = Cw20ExecuteMsg::Transfer {
+            recipient: String::from("taker"),
+            amount: Uint128::new(20),
+        };
+        assert_eq!(
+            res.messages[2],
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: cw20_token_contract.clone(),
+                msg: to_binary(&refund).unwrap(),
+                funds: vec![],
+            })
+        );
+
+        // Order 2 did not and rejects the overpayment
+        let err = execute(deps.as_mut(), mock_env(), info, close(2)).unwrap_err();
+        assert!(matches!(err, ContractError::OrderUnmatched {}));
+    }
+
+    #[test]
+    fn refund_native_overpayment() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let msg = OpenOrderMsg {
+            taker_token: GenericBalance {
+                native: vec![coin(100, "native"), coin(50, "uusd")],
+                cw20: vec![],
+            },
+            ..Default::default()
+        };
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(12345),
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+
+        let msg = ExecuteMsg::CloseOrder(CloseOrderMsg {
+            order_id: 1,
+            ..Default::default()
+        });
+        // Underpaying is still rejected
+        let info = mock_info("taker", &[coin(99, "native"), coin(50, "uusd")]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::OrderUnmatched {}));
+
+        let info = mock_info("taker", &[coin(120, "native"), coin(50, "uusd")]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(3, res.messages.len());
+        assert_eq!(
+            res.messages[0],
+            SubMsg::new(BankMsg::Send {
+                to_address: String::from("maker"),
+                amount: vec![coin(100, "native"), coin(50, "uusd")],
+            })
+        );
+        assert_eq!(
+            res.messages[2],
+            SubMsg::new(BankMsg::Send {
+                to_address: String::from("taker"),
+                amount: coins(20, "native"),
+            })
+        );
+    }
+
+    #[test]
+    fn query_depth_aggregates_price_levels() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        // Prices of 2, 3, 2 and 4, plus an order for another token
+        for (maker_amount, taker_amount, denom) in [
+            (100, 200u128, "my-cw20-token"),
+            (100, 300, "my-cw20-token"),
+            (50, 100, "my-cw20-token"),
+            (100, 400, "my-cw20-token"),
+            (100, 100, "xyz-token"),
+        ] {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from(denom), taker_amount.into()),
+                ..Default::default()
+            };
+            let info = mock_info("maker", &coins(maker_amount, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+
+        let res = query_depth(
+            deps.as_ref(),
+            &mock_env(),
+            String::from("native"),
+            String::from("my-cw20-token"),
+            2,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                DepthLevel {
+                    price: Decimal::from_ratio(2u128, 1u128),
+                    maker_amount: Uint128::new(150),
+                },
+                DepthLevel {
+                    price: Decimal::from_ratio(3u128, 1u128),
+                    maker_amount: Uint128::new(100),
+                },
+            ],
+            res.levels
+        );
+    }
+
+    #[test]
+    fn list_orders_newest_first() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        for _ in 0..3 {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+                ..Default::default()
+            };
+            let info = mock_info("maker", &coins(100, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+
+        let res = query_list_orders_desc(deps.as_ref(), None, Some(2)).unwrap();
+        let ids: Vec<u64> = res.orders.iter().map(|order| order.id).collect();
+        assert_eq!(vec![3, 2], ids);
+
+        let res = query_list_orders_desc(deps.as_ref(), Some(2), None).unwrap();
+        let ids: Vec<u64> = res.orders.iter().map(|order| order.id).collect();
+        assert_eq!(vec![1], ids);
+    }
+
+    #[test]
+    fn balance_from_generic_balance() {
+        let native = GenericBalance {
+            native: coins(100, "native"),
+            cw20: vec![],
+        };
+        assert_eq!(
+            Balance::Native(NativeBalance(coins(100, "native"))),
+            Balance::try_from(&native).unwrap()
+        );
+
+        let cw20 = create_cw20_tokens(&String::from("my-cw20-token"), Uint128::new(100));
+        assert_eq!(
+            Balance::Cw20(cw20.cw20[0].clone()),
+            Balance::try_from(&cw20).unwrap()
+        );
+
+        let mixed = GenericBalance {
+            native: coins(100, "native"),
+            cw20: cw20.cw20,
+        };
+        Balance::try_from(&mixed).unwrap_err();
+    }
+
+    #[test]
+    fn close_with_wrong_cw20_fails_before_other_checks() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        // No oracle is configured, so a close that got as far as pricing would fail
+        // with OracleUnavailable
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 1u128.into()),
+            price_in_usd: Some(Uint128::new(100)),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info("wrong-token", &[]);
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive.clone()),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::OrderUnmatched {}));
+
+        let info = mock_info("my-cw20-token", &[]);
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::OracleUnavailable {}));
+    }
+
+    #[test]
+    fn close_expired_order_fails() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let expires_at_height = mock_env().block.height + 10;
+        for _ in 0..2 {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+                expires_at_height: Some(expires_at_height),
+                ..Default::default()
+            };
+            let info = mock_info("maker", &coins(100, "native"));
+            execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        }
+
+        let close = |order_id| {
+            let receive = Cw20ReceiveMsg {
+                sender: String::from("taker"),
+                amount: Uint128::new(100),
+                msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                    order_id,
+                    ..Default::default()
+                }))
+                .unwrap(),
+            };
+            ExecuteMsg::Receive(receive)
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        let mut env = mock_env();
+        env.block.height = expires_at_height - 1;
+        execute(deps.as_mut(), env.clone(), info.clone(), close(1)).unwrap();
+
+        env.block.height = expires_at_height;
+        let err = execute(deps.as_mut(), env, info, close(2)).unwrap_err();
+        assert!(matches!(err, ContractError::OrderExpired {}));
+    }
+
+    #[test]
+    fn order_response_shows_close_conditions() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let remainder = Cw20Coin {
+            address: String::from("abc-token"),
+            amount: Uint128::new(90),
+        };
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+            require_taker_ack: Some(String::from("nonce")),
+            price_in_usd: Some(Uint128::new(50)),
+            remainder_from_allowance: Some(remainder.clone()),
+            deposit_to_vault: Some(String::from("vault")),
+            group_id: Some(String::from("ladder")),
+            expires_at_height: Some(mock_env().block.height + 10),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(10, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let order = query_order(deps.as_ref(), 1).unwrap();
+        assert_eq!(Some(mock_env().block.height + 10), order.expires_at_height);
+        assert_eq!(Some(Uint128::new(50)), order.price_in_usd);
+        assert_eq!(
+            Some(Cw20CoinVerified {
+                address: Addr::unchecked(remainder.address),
+                amount: remainder.amount,
+            }),
+            order.remainder_from_allowance
+        );
+        assert_eq!(Some(String::from("ladder")), order.group_id);
+        assert_eq!(Some(String::from("nonce")), order.require_taker_ack);
+        assert_eq!(Some(Addr::unchecked("vault")), order.deposit_to_vault);
+    }
+
+    #[test]
+    fn expired_orders_leave_the_book() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let height = mock_env().block.height;
+        let open = |expires_at_height| {
+            ExecuteMsg::OpenOrder(OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+                expires_at_height: Some(expires_at_height),
+                ..Default::default()
+            })
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), open(height)).unwrap_err();
+        assert_eq!(
+            ContractError::OrderInvalidReason(OrderInvalidReason::ExpiryPassed).to_string(),
+            err.to_string()
+        );
+        execute(deps.as_mut(), mock_env(), info, open(height + 10)).unwrap();
+
+        let mut env = mock_env();
+        env.block.height = height + 10;
+
+        // Deposits toward the expired order are refused
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(40),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::OrderExpired {}));
+
+        let best = |env: &Env| {
+            query_best_order(
+                deps.as_ref(),
+                env,
+                String::from("native"),
+                String::from("my-cw20-token"),
+                None,
+            )
+            .unwrap()
+            .order
+        };
+        assert!(best(&mock_env()).is_some());
+        assert!(best(&env).is_none());
+
+        let stats = query_stats(deps.as_ref(), &env, None).unwrap();
+        assert_eq!(0, stats.open_orders);
+        assert_eq!(1, stats.expired_orders);
+        assert_eq!(0, stats.closed_orders);
+        assert_eq!(0, stats.active_assets);
+        let balance = create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into());
+        let res = query_fillable_count(deps.as_ref(), &env, balance, None).unwrap();
+        assert_eq!(0, res.count);
+        let res = query_search(
+            deps.as_ref(),
+            &env,
+            None,
+            None,
+            Some(true),
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert!(res.orders.is_empty());
+    }
+
+    #[test]
+    fn close_order_respects_max_price() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        // 200 cw20 for 100 native, a price of 2
+        let cw20_token_contract = String::from("my-cw20-token");
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&cw20_token_contract, 200u128.into()),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let close = |max_price| {
+            let receive = Cw20ReceiveMsg {
+                sender: String::from("taker"),
+                amount: Uint128::new(200),
+                msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                    order_id: 1,
+                    max_price: Some(max_price),
+                    ..Default::default()
+                }))
+                .unwrap(),
+            };
+            ExecuteMsg::Receive(receive)
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            close(Decimal::percent(150)),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::SlippageExceeded {}));
+
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            close(Decimal::percent(200)),
+        )
+        .unwrap();
+        assert_eq!(2, res.messages.len());
+        assert!(!query_order(deps.as_ref(), 1).unwrap().is_open);
+    }
+
+    #[test]
+    fn close_order_pulls_remainder_from_allowance() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        // 10 native escrowed as a bond, 90 abc-token pulled at close
+        let cw20_token_contract = String::from("my-cw20-token");
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&cw20_token_contract, 100u128.into()),
+            remainder_from_allowance: Some(Cw20Coin {
+                address: String::from("abc-token"),
+                amount: Uint128::new(90),
+            }),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(10, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+        assert_eq!(3, res.messages.len());
+        assert_eq!(
+            res.messages[1],
+            SubMsg::new(BankMsg::Send {
+                to_address: String::from("taker"),
+                amount: coins(10, "native"),
+            })
+        );
+        let transfer_from = Cw20ExecuteMsg::TransferFrom {
+            owner: String::from("maker"),
+            recipient: String::from("taker"),
+            amount: Uint128::new(90),
+        };
+        assert_eq!(
+            res.messages[2],
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: String::from("abc-token"),
+                msg: to_binary(&transfer_from).unwrap(),
+                funds: vec![],
+            })
+        );
+        // Both legs the maker gave count toward the volume
+        for (asset, amount) in [("native", 10), ("abc-token", 90)] {
+            assert_eq!(
+                Uint128::new(amount),
+                VOLUME.load(&deps.storage, asset).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn record_volume_saturates() {
+        let mut deps = mock_deps();
+        VOLUME
+            .save(&mut deps.storage, "native", &Uint128::MAX)
+            .unwrap();
+        let balance = GenericBalance {
+            native: coins(1, "native"),
+            cw20: vec![],
+        };
+        record_volume(&mut deps.storage, &balance).unwrap();
+        assert_eq!(Uint128::MAX, VOLUME.load(&deps.storage, "native").unwrap());
+    }
+
+    #[test]
+    fn settlement_preview_matches_close() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let cw20_token_contract = String::from("my-cw20-token");
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&cw20_token_contract, 100u128.into()),
+            remainder_from_allowance: Some(Cw20Coin {
+                address: String::from("abc-token"),
+                amount: Uint128::new(90),
+            }),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(10, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let msg = QueryMsg::SettlementPreview { order_id: 1 };
+        let res = query(deps.as_ref(), mock_env(), msg.clone()).unwrap();
+        let res: SettlementPreviewResponse = from_binary(&res).unwrap();
+        assert_eq!(
+            vec![
+                SettlementTransfer {
+                    recipient: SettlementRecipient::Maker {
+                        address: Addr::unchecked("maker"),
+                    },
+                    asset: cw20_token_contract.clone(),
+                    amount: Uint128::new(100),
+                },
+                SettlementTransfer {
+                    recipient: SettlementRecipient::Taker {},
+                    asset: String::from("native"),
+                    amount: Uint128::new(10),
+                },
+                SettlementTransfer {
+                    recipient: SettlementRecipient::Taker {},
+                    asset: String::from("abc-token"),
+                    amount: Uint128::new(90),
+                },
+            ],
+            res.transfers
+        );
+
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+        let transfer = Cw20ExecuteMsg::Transfer {
+            recipient: String::from("maker"),
+            amount: Uint128::new(100),
+        };
+        let transfer_from = Cw20ExecuteMsg::TransferFrom {
+            owner: String::from("maker"),
+            recipient: String::from("taker"),
+            amount: Uint128::new(90),
+        };
+        assert_eq!(
+            vec![
+                SubMsg::new(WasmMsg::Execute {
+                    contract_addr: cw20_token_contract,
+                    msg: to_binary(&transfer).unwrap(),
+                    funds: vec![],
+                }),
+                SubMsg::new(BankMsg::Send {
+                    to_address: String::from("taker"),
+                    amount: coins(10, "native"),
+                }),
+                SubMsg::new(WasmMsg::Execute {
+                    contract_addr: String::from("abc-token"),
+                    msg: to_binary(&transfer_from).unwrap(),
+                    funds: vec![],
+                }),
+            ],
+            res.messages
+        );
+
+        // Closed orders have nothing left to settle
+        query(deps.as_ref(), mock_env(), msg).unwrap_err();
+    }
+
+    #[test]
+    fn allowance_backed_order_is_not_transferable_or_priced() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("xyz-token"), 1u128.into()),
+            remainder_from_allowance: Some(Cw20Coin {
+                address: String::from("abc-token"),
+                amount: Uint128::new(1_000_000),
+            }),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(1, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let msg = ExecuteMsg::TransferOrder {
+            order_id: 1,
+            new_maker: String::from("victim"),
+        };
+        let info = mock_info("maker", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert!(matches!(err, ContractError::NotTransferable {}));
+
+        // The bond alone does not price the order
+        let order = orders().load(&deps.storage, 1u64.into()).unwrap();
+        assert_eq!(None, order.implied_price(&[]));
+        let msg = QueryMsg::BestOrder {
+            maker_denom: String::from("native"),
+            taker_denom: String::from("xyz-token"),
+            start_after: None,
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let res: BestOrderResponse = from_binary(&res).unwrap();
+        assert_eq!(None, res.order);
+
+        // A remainder the maker cannot cover is rejected at open
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("xyz-token"), 1u128.into()),
+            remainder_from_allowance: Some(Cw20Coin {
+                address: String::from("abc-token"),
+                amount: Uint128::new(1_000_001),
+            }),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(1, "native"));
+        let err = execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap_err();
+        assert_eq!(
+            ContractError::OrderInvalidReason(OrderInvalidReason::UnbackedRemainder).to_string(),
+            err.to_string()
+        );
     }
-}
 
-fn query_order(deps: Deps, id: u64) -> StdResult<OrderResponse> {
-    let order = ORDERS.load(deps.storage, id.into())?;
-    Ok(OrderResponse {
-        maker_address: order.maker_address,
-        maker_token: order.maker_token,
-        taker_token: order.taker_token,
-        target_address: order.target_address,
-        is_open: order.is_open,
-    })
-}
+    #[test]
+    fn depth_overflow_fails_the_query() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+        deps.querier.update_wasm(mock_wasm(u128::MAX));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use cosmwasm_std::testing::{
-        mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage,
-    };
-    use cosmwasm_std::{coins, CosmosMsg, Empty, OwnedDeps, Uint128};
-    use cw0::NativeBalance;
+        // Each order offers more than half of the Uint128 range
+        for _ in 0..2 {
+            let msg = OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("xyz-token"), 1u128.into()),
+                remainder_from_allowance: Some(Cw20Coin {
+                    address: String::from("abc-token"),
+                    amount: Uint128::new(u128::MAX / 2),
+                }),
+                ..Default::default()
+            };
+            let receive = Cw20ReceiveMsg {
+                sender: String::from("maker"),
+                amount: Uint128::new(1),
+                msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+            };
+            let info = mock_info("abc-token", &[]);
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                info,
+                ExecuteMsg::Receive(receive),
+            )
+            .unwrap();
+        }
+
+        let msg = QueryMsg::Depth {
+            base_denom: String::from("abc-token"),
+            taker_denom: String::from("xyz-token"),
+            levels: 10,
+            start_after: None,
+        };
+        let err = query(deps.as_ref(), mock_env(), msg).unwrap_err();
+        assert!(matches!(err, StdError::Overflow { .. }));
+    }
 
     #[test]
-    fn order_native_to_cw20() {
-        let mut deps = mock_dependencies(&[]);
+    fn close_order_deposits_proceeds_into_vault() {
+        let mut deps = mock_deps();
         instantiate_contract(&mut deps);
 
         let cw20_token_contract = String::from("my-cw20-token");
-        let cw20_token_amount = Uint128::new(12345);
-        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, cw20_token_amount);
-
         let msg = OpenOrderMsg {
-            taker_token: cw20_tokens.clone(),
-            target_address: None,
+            taker_token: create_cw20_tokens(&cw20_token_contract, 100u128.into()),
+            deposit_to_vault: Some(String::from("vault")),
+            ..Default::default()
         };
-        let maker = String::from("maker");
-        let balance = coins(100, "native");
-        let info = mock_info(&maker, &balance);
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info(&cw20_token_contract, &[]);
         let res = execute(
             deps.as_mut(),
             mock_env(),
             info,
-            ExecuteMsg::OpenOrder(msg.clone()),
+            ExecuteMsg::Receive(receive),
         )
         .unwrap();
-        assert_eq!(0, res.messages.len());
-        assert_eq!(("method", "open_order"), res.attributes[0]);
-        assert_eq!(("order_id", "1"), res.attributes[1]);
-
-        // Check that order is correctly opened
-        let order = query_order(deps.as_ref(), 1).unwrap();
-        assert_eq!(&maker, order.maker_address.as_str());
-        assert_eq!(balance, order.maker_token.native);
-        assert_eq!(cw20_tokens.cw20, order.taker_token.cw20);
-        assert_eq!(None, order.target_address);
-        assert_eq!(true, order.is_open);
-
-        // Close the open order
-        let taker = String::from("taker");
-        let receive = Cw20ReceiveMsg {
-            sender: taker.clone(),
-            amount: cw20_token_amount,
-            msg: to_binary(&ExecuteMsg::CloseOrder { order_id: 1 }).unwrap(),
-        };
-        let info = mock_info(&cw20_token_contract, &[]);
-        let msg = ExecuteMsg::Receive(receive.clone());
-        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
         assert_eq!(2, res.messages.len());
-        assert_eq!(("method", "close_order"), res.attributes[0]);
-        assert_eq!(("order_id", "1"), res.attributes[1]);
-        let send_msg = Cw20ExecuteMsg::Transfer {
-            recipient: maker,
-            amount: cw20_token_amount,
+        let send = Cw20ExecuteMsg::Send {
+            contract: String::from("vault"),
+            amount: Uint128::new(100),
+            msg: to_binary(&VaultMsg::Deposit {
+                recipient: String::from("maker"),
+            })
+            .unwrap(),
         };
         assert_eq!(
             res.messages[0],
-            SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: cw20_token_contract.clone(),
-                msg: to_binary(&send_msg).unwrap(),
-                funds: vec![]
-            }))
-        );
-        assert_eq!(
-            res.messages[1],
-            SubMsg::new(BankMsg::Send {
-                to_address: taker,
-                amount: balance,
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: cw20_token_contract,
+                msg: to_binary(&send).unwrap(),
+                funds: vec![],
             })
         );
-
-        // Check that order is closed
-        let order = query_order(deps.as_ref(), 1).unwrap();
-        assert_eq!(false, order.is_open);
     }
 
     #[test]
-    fn order_cw20_to_native() {
-        let mut deps = mock_dependencies(&[]);
+    fn close_order_calls_back_taker() {
+        let mut deps = mock_deps();
         instantiate_contract(&mut deps);
 
-        let mut native_tokens = GenericBalance::default();
-        native_tokens.add_tokens(Balance::Native(NativeBalance(coins(100, "native"))));
+        let cw20_token_contract = String::from("my-cw20-token");
         let msg = OpenOrderMsg {
-            taker_token: native_tokens.clone(),
-            target_address: None,
+            taker_token: create_cw20_tokens(&cw20_token_contract, 100u128.into()),
+            ..Default::default()
         };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
 
-        let cw20_token_contract = String::from("my-cw20-token");
-        let cw20_token_amount = Uint128::new(12345);
-        let maker = String::from("maker");
         let receive = Cw20ReceiveMsg {
-            sender: maker.clone(),
-            amount: cw20_token_amount,
-            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+            sender: String::from("taker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                taker_callback: Some(String::from("taker-hook")),
+                ..Default::default()
+            }))
+            .unwrap(),
         };
         let info = mock_info(&cw20_token_contract, &[]);
-        let msg = ExecuteMsg::Receive(receive.clone());
-        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
-        assert_eq!(0, res.messages.len());
-        assert_eq!(("method", "open_order"), res.attributes[0]);
-        assert_eq!(("order_id", "1"), res.attributes[1]);
-
-        // Check that order is correctly opened
-        let order = query_order(deps.as_ref(), 1).unwrap();
-        assert_eq!(maker, order.maker_address.as_str());
-        assert_eq!(
-            create_cw20_tokens(&cw20_token_contract, cw20_token_amount).cw20,
-            order.maker_token.cw20
-        );
-        assert_eq!(native_tokens.native, order.taker_token.native);
-        assert_eq!(None, order.target_address);
-        assert_eq!(true, order.is_open);
-
-        // Close the open order
-        let taker = String::from("taker");
-        let balance = coins(100, "native");
-        let info = mock_info(&taker, &balance);
         let res = execute(
             deps.as_mut(),
             mock_env(),
             info,
-            ExecuteMsg::CloseOrder { order_id: 1 },
+            ExecuteMsg::Receive(receive),
         )
         .unwrap();
-        assert_eq!(2, res.messages.len());
-        assert_eq!(("method", "close_order"), res.attributes[0]);
-        assert_eq!(("order_id", "1"), res.attributes[1]);
-        assert_eq!(
-            res.messages[0],
-            SubMsg::new(BankMsg::Send {
-                to_address: maker,
-                amount: balance,
-            })
-        );
-        let send_msg = Cw20ExecuteMsg::Transfer {
-            recipient: taker,
-            amount: cw20_token_amount,
+        assert_eq!(3, res.messages.len());
+        let callback = TakerCallbackMsg::Filled {
+            order_id: 1,
+            maker_token: GenericBalance {
+                native: coins(100, "native"),
+                cw20: vec![],
+            },
         };
         assert_eq!(
-            res.messages[1],
-            SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: cw20_token_contract.clone(),
-                msg: to_binary(&send_msg).unwrap(),
-                funds: vec![]
-            }))
+            res.messages[2],
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: String::from("taker-hook"),
+                msg: to_binary(&callback).unwrap(),
+                funds: vec![],
+            })
         );
+    }
 
-        // Check that order is closed
-        let order = query_order(deps.as_ref(), 1).unwrap();
-        assert_eq!(false, order.is_open);
+    #[test]
+    fn query_fillable_count_checks_each_order() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        for amount in [100, 150, 200] {
+            let mut native_tokens = GenericBalance::default();
+            native_tokens.add_tokens(Balance::Native(NativeBalance(coins(amount, "native"))));
+            let msg = OpenOrderMsg {
+                taker_token: native_tokens,
+                ..Default::default()
+            };
+            let receive = Cw20ReceiveMsg {
+                sender: String::from("maker"),
+                amount: Uint128::new(100),
+                msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+            };
+            let info = mock_info("my-cw20-token", &[]);
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                info,
+                ExecuteMsg::Receive(receive),
+            )
+            .unwrap();
+        }
+
+        let balance = GenericBalance {
+            native: coins(150, "native"),
+            cw20: vec![],
+        };
+        let res = query_fillable_count(deps.as_ref(), &mock_env(), balance, None).unwrap();
+        assert_eq!(2, res.count);
     }
 
     #[test]
-    fn order_cw20_to_cw20() {
-        let mut deps = mock_dependencies(&[]);
+    fn usd_priced_orders_stay_out_of_the_book() {
+        let mut deps = mock_deps();
         instantiate_contract(&mut deps);
 
-        let xyz_token_contract = String::from("xyz-token");
-        let xyz_token_amount = Uint128::new(12345);
-        let xyz_tokens = create_cw20_tokens(&xyz_token_contract, xyz_token_amount);
-        let msg = OpenOrderMsg {
-            taker_token: xyz_tokens.clone(),
-            target_address: None,
+        // Asking 1000 USD behind a placeholder demand of 1, then an honest 1:1 order
+        for (taker_amount, price_in_usd) in [(1u128, Some(Uint128::new(1000))), (100, None)] {
+            let msg = OpenOrderMsg {
+                taker_token: GenericBalance {
+                    native: coins(taker_amount, "native"),
+                    cw20: vec![],
+                },
+                price_in_usd,
+                ..Default::default()
+            };
+            let receive = Cw20ReceiveMsg {
+                sender: String::from("maker"),
+                amount: Uint128::new(100),
+                msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+            };
+            let info = mock_info("my-cw20-token", &[]);
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                info,
+                ExecuteMsg::Receive(receive),
+            )
+            .unwrap();
+        }
+
+        let res = query_best_order(
+            deps.as_ref(),
+            &mock_env(),
+            String::from("my-cw20-token"),
+            String::from("native"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(2, res.order.unwrap().id);
+        let balance = GenericBalance {
+            native: coins(1, "native"),
+            cw20: vec![],
         };
+        let res = query_fillable_count(deps.as_ref(), &mock_env(), balance, None).unwrap();
+        assert_eq!(0, res.count);
 
-        let abc_token_contract = String::from("abc-token");
-        let abc_token_amount = Uint128::new(12345);
-        let maker = String::from("maker");
+        // An oracle price of 10^-18 USD would demand more than a Uint128 holds
+        let msg = ExecuteMsg::UpdateConfig {
+            owner: None,
+            open_cooldown_blocks: None,
+            clear_open_cooldown: false,
+            denom_aliases: None,
+            asset_decimals: None,
+            blacklist: None,
+            oracle: Some(String::from("oracle")),
+            clear_oracle: false,
+            min_prices: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), msg).unwrap();
+        let msg = OpenOrderMsg {
+            taker_token: GenericBalance {
+                native: coins(1, "native"),
+                cw20: vec![],
+            },
+            price_in_usd: Some(Uint128::new(u128::MAX / 2)),
+            ..Default::default()
+        };
         let receive = Cw20ReceiveMsg {
-            sender: maker.clone(),
-            amount: abc_token_amount,
+            sender: String::from("maker"),
+            amount: Uint128::new(100),
             msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
         };
-        let info = mock_info(&abc_token_contract, &[]);
-        let msg = ExecuteMsg::Receive(receive.clone());
-        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
-        assert_eq!(0, res.messages.len());
-        assert_eq!(("method", "open_order"), res.attributes[0]);
-        assert_eq!(("order_id", "1"), res.attributes[1]);
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            ExecuteMsg::Receive(receive),
+        )
+        .unwrap();
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::Smart { contract_addr, .. } if contract_addr == "oracle" => {
+                let price = OraclePriceResponse {
+                    price: Decimal::from_ratio(1u128, 1_000_000_000_000_000_000u128),
+                };
+                SystemResult::Ok(ContractResult::Ok(to_binary(&price).unwrap()))
+            }
+            query => mock_wasm(1_000_000)(query),
+        });
+        let close = ExecuteMsg::CloseOrder(CloseOrderMsg {
+            order_id: 3,
+            ..Default::default()
+        });
+        let info = mock_info("taker", &coins(1, "native"));
+        let err = execute(deps.as_mut(), mock_env(), info, close).unwrap_err();
+        assert!(matches!(err, ContractError::UsdDemandOutOfRange {}));
+    }
 
-        // Check that order is correctly opened
-        let order = query_order(deps.as_ref(), 1).unwrap();
-        assert_eq!(maker, order.maker_address.as_str());
-        assert_eq!(
-            create_cw20_tokens(&abc_token_contract, abc_token_amount).cw20,
-            order.maker_token.cw20
-        );
-        assert_eq!(xyz_tokens.cw20, order.taker_token.cw20);
-        assert_eq!(None, order.target_address);
-        assert_eq!(true, order.is_open);
+    #[test]
+    fn close_usd_priced_order() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
 
-        // Close the open order
-        let taker = String::from("taker");
+        // The demanded amount is a placeholder, only the denom matters
+        let mut native_tokens = GenericBalance::default();
+        native_tokens.add_tokens(Balance::Native(NativeBalance(coins(1, "native"))));
+        let msg = OpenOrderMsg {
+            taker_token: native_tokens,
+            price_in_usd: Some(Uint128::new(100)),
+            ..Default::default()
+        };
         let receive = Cw20ReceiveMsg {
-            sender: taker.clone(),
-            amount: xyz_token_amount,
-            msg: to_binary(&ExecuteMsg::CloseOrder { order_id: 1 }).unwrap(),
+            sender: String::from("maker"),
+            amount: Uint128::new(12345),
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
         };
-        let info = mock_info(&xyz_token_contract, &[]);
-        let res = execute(
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
             deps.as_mut(),
             mock_env(),
             info,
             ExecuteMsg::Receive(receive),
         )
         .unwrap();
-        assert_eq!(2, res.messages.len());
-        assert_eq!(("method", "close_order"), res.attributes[0]);
-        assert_eq!(("order_id", "1"), res.attributes[1]);
-        let send_msg_0 = Cw20ExecuteMsg::Transfer {
-            recipient: maker,
-            amount: xyz_token_amount,
-        };
-        assert_eq!(
-            res.messages[0],
-            SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: xyz_token_contract,
-                msg: to_binary(&send_msg_0).unwrap(),
-                funds: vec![]
-            }))
-        );
-        let send_msg_1 = Cw20ExecuteMsg::Transfer {
-            recipient: taker,
-            amount: abc_token_amount,
+
+        // No oracle configured yet
+        let close = ExecuteMsg::CloseOrder(CloseOrderMsg {
+            order_id: 1,
+            ..Default::default()
+        });
+        let info = mock_info("taker", &coins(200, "native"));
+        let err = execute(deps.as_mut(), mock_env(), info, close.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::OracleUnavailable {}));
+
+        let msg = ExecuteMsg::UpdateConfig {
+            owner: None,
+            open_cooldown_blocks: None,
+            clear_open_cooldown: false,
+            denom_aliases: None,
+            asset_decimals: None,
+            blacklist: None,
+            oracle: Some(String::from("oracle")),
+            clear_oracle: false,
+            min_prices: None,
         };
-        assert_eq!(
-            res.messages[1],
-            SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: abc_token_contract,
-                msg: to_binary(&send_msg_1).unwrap(),
-                funds: vec![]
-            }))
-        );
+        execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), msg).unwrap();
 
-        // Check that order is closed
+        // 100 USD at 0.5 USD per unit is 200 units
+        let info = mock_info("taker", &coins(199, "native"));
+        let err = execute(deps.as_mut(), mock_env(), info, close.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::OrderUnmatched {}));
+        let info = mock_info("taker", &coins(200, "native"));
+        let res = execute(deps.as_mut(), mock_env(), info, close).unwrap();
+        assert_eq!(
+            res.messages[0],
+            SubMsg::new(BankMsg::Send {
+                to_address: String::from("maker"),
+                amount: coins(200, "native"),
+            })
+        );
         let order = query_order(deps.as_ref(), 1).unwrap();
-        assert_eq!(false, order.is_open);
+        assert_eq!(coins(200, "native"), order.taker_token.native);
     }
 
     #[test]
-    fn open_multiple_orders() {
-        let mut deps = mock_dependencies(&[]);
+    fn split_and_batch_close_usd_priced_orders() {
+        let mut deps = mock_deps();
         instantiate_contract(&mut deps);
-
-        let cw20_token_contract = String::from("my-cw20-token");
-        let cw20_token_amount = Uint128::new(12345);
-        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, cw20_token_amount);
+        let msg = ExecuteMsg::UpdateConfig {
+            owner: None,
+            open_cooldown_blocks: None,
+            clear_open_cooldown: false,
+            denom_aliases: None,
+            asset_decimals: None,
+            blacklist: None,
+            oracle: Some(String::from("oracle")),
+            clear_oracle: false,
+            min_prices: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), msg).unwrap();
 
         let msg = OpenOrderMsg {
-            taker_token: cw20_tokens.clone(),
-            target_address: None,
+            taker_token: GenericBalance {
+                native: coins(1, "native"),
+                cw20: vec![],
+            },
+            price_in_usd: Some(Uint128::new(200)),
+            ..Default::default()
         };
-        let maker = String::from("maker");
-        let first_order_balance = coins(100, "native");
-        let res = execute(
-            deps.as_mut(),
-            mock_env(),
-            mock_info(&maker, &first_order_balance),
-            ExecuteMsg::OpenOrder(msg.clone()),
-        )
-        .unwrap();
-        assert_eq!(0, res.messages.len());
-        assert_eq!(("method", "open_order"), res.attributes[0]);
-        assert_eq!(("order_id", "1"), res.attributes[1]);
-
-        let second_order_balance = coins(200, "native");
-        let res = execute(
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        execute(
             deps.as_mut(),
             mock_env(),
-            mock_info(&maker, &second_order_balance),
-            ExecuteMsg::OpenOrder(msg.clone()),
+            info,
+            ExecuteMsg::Receive(receive),
         )
         .unwrap();
-        assert_eq!(0, res.messages.len());
-        assert_eq!(("method", "open_order"), res.attributes[0]);
-        assert_eq!(("order_id", "2"), res.attributes[1]);
 
-        let third_order_balance = coins(300, "native");
-        let res = execute(
-            deps.as_mut(),
-            mock_env(),
-            mock_info(&maker, &third_order_balance),
-            ExecuteMsg::OpenOrder(msg.clone()),
-        )
-        .unwrap();
-        assert_eq!(0, res.messages.len());
-        assert_eq!(("method", "open_order"), res.attributes[0]);
-        assert_eq!(("order_id", "3"), res.attributes[1]);
+        // The USD demand is divided along with the escrow
+        let msg = ExecuteMsg::SplitOrder {
+            order_id: 1,
+            first_fraction_bps: 5_000,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("maker", &[]), msg).unwrap();
+        for order_id in [1u64, 2] {
+            let order = orders().load(&deps.storage, order_id.into()).unwrap();
+            assert_eq!(Some(Uint128::new(100)), order.price_in_usd);
+        }
 
-        // Check that orders are correctly opened
-        let order = query_order(deps.as_ref(), 1).unwrap();
-        assert_eq!(first_order_balance, order.maker_token.native);
+        // 100 USD at 0.5 USD per unit is 200 units for each order
+        let msg = ExecuteMsg::CloseOrders {
+            order_ids: vec![1, 2],
+        };
+        let info = mock_info("taker", &coins(2, "native"));
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::OrderUnmatched {}));
+        let info = mock_info("taker", &coins(400, "native"));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
         let order = query_order(deps.as_ref(), 2).unwrap();
-        assert_eq!(second_order_balance, order.maker_token.native);
-        let order = query_order(deps.as_ref(), 3).unwrap();
-        assert_eq!(third_order_balance, order.maker_token.native);
+        assert_eq!(coins(200, "native"), order.taker_token.native);
     }
 
     #[test]
-    fn close_order_with_invalid_target_address_fails() {
-        let mut deps = mock_dependencies(&[]);
+    fn open_order_with_same_cw20_token_fails() {
+        let mut deps = mock_deps();
         instantiate_contract(&mut deps);
 
         let cw20_token_contract = String::from("my-cw20-token");
@@ -548,144 +6557,480 @@ mod tests {
 
         let msg = OpenOrderMsg {
             taker_token: cw20_tokens.clone(),
-            target_address: Some(String::from("target")),
+            target_address: None,
+            ..Default::default()
         };
         let maker = String::from("maker");
-        let balance = coins(100, "native");
-        let info = mock_info(&maker, &balance);
-        let res = execute(
-            deps.as_mut(),
-            mock_env(),
-            info,
-            ExecuteMsg::OpenOrder(msg.clone()),
-        )
-        .unwrap();
-        assert_eq!(0, res.messages.len());
-        assert_eq!(("method", "open_order"), res.attributes[0]);
-        assert_eq!(("order_id", "1"), res.attributes[1]);
-
-        // Close the open order
         let receive = Cw20ReceiveMsg {
-            sender: String::from("taker"),
-            amount: Uint128::new(12345),
-            msg: to_binary(&ExecuteMsg::CloseOrder { order_id: 1 }).unwrap(),
+            sender: maker.clone(),
+            amount: cw20_token_amount,
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
         };
         let info = mock_info(&cw20_token_contract, &[]);
         let msg = ExecuteMsg::Receive(receive.clone());
         let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
-        assert!(matches!(err, ContractError::OrderReserved {}));
+        assert!(matches!(
+            err,
+            ContractError::OrderInvalidReason(OrderInvalidReason::MakerTakerSameToken)
+        ));
     }
 
     #[test]
-    fn close_order_with_valid_target_address_succeeds() {
-        let mut deps = mock_dependencies(&[]);
+    fn open_invalid_order_fails_with_reason() {
+        let mut deps = mock_deps();
         instantiate_contract(&mut deps);
 
-        let cw20_token_contract = String::from("my-cw20-token");
-        let cw20_token_amount = Uint128::new(12345);
-        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, cw20_token_amount);
+        let mut native_tokens = GenericBalance::default();
+        native_tokens.add_tokens(Balance::Native(NativeBalance(coins(100, "native"))));
+        let mut mixed_tokens = create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into());
+        mixed_tokens.add_tokens(Balance::Native(NativeBalance(coins(100, "native"))));
+
+        let cases = [
+            (GenericBalance::default(), OrderInvalidReason::EmptyTaker),
+            (
+                create_cw20_tokens(&String::from("my-cw20-token"), Uint128::zero()),
+                OrderInvalidReason::ZeroTakerAmount,
+            ),
+            (mixed_tokens, OrderInvalidReason::MixedTaker),
+            (native_tokens, OrderInvalidReason::BothNative),
+        ];
+        for (taker_token, reason) in cases {
+            let msg = OpenOrderMsg {
+                taker_token,
+                target_address: None,
+                ..Default::default()
+            };
+            let info = mock_info("maker", &coins(100, "other"));
+            let err =
+                execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap_err();
+            match err {
+                ContractError::OrderInvalidReason(actual) => assert_eq!(reason, actual),
+                err => panic!("unexpected error: {}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn open_order_with_zero_native_funds_fails() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
 
         let msg = OpenOrderMsg {
-            taker_token: cw20_tokens.clone(),
-            target_address: Some(String::from("target")),
+            taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+            ..Default::default()
         };
-        let maker = String::from("maker");
-        let balance = coins(100, "native");
-        let info = mock_info(&maker, &balance);
-        let res = execute(
+        let info = mock_info("maker", &[coin(100, "native"), coin(0, "uusd")]);
+        let err = execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap_err();
+        assert!(matches!(
+            err,
+            ContractError::OrderInvalidReason(OrderInvalidReason::ZeroMakerAmount)
+        ));
+    }
+
+    #[test]
+    fn open_order_with_multiple_cw20_takers_fails() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let mut cw20_tokens = create_cw20_tokens(&String::from("xyz-token"), 100u128.into());
+        cw20_tokens.add_tokens(Balance::Cw20(Cw20CoinVerified {
+            address: Addr::unchecked("abc-token"),
+            amount: Uint128::new(100),
+        }));
+        let msg = OpenOrderMsg {
+            taker_token: cw20_tokens,
+            ..Default::default()
+        };
+
+        // Native maker
+        let info = mock_info("maker", &coins(100, "native"));
+        let open = ExecuteMsg::OpenOrder(msg.clone());
+        let err = execute(deps.as_mut(), mock_env(), info, open).unwrap_err();
+        assert!(matches!(
+            err,
+            ContractError::OrderInvalidReason(OrderInvalidReason::MultipleCw20Taker)
+        ));
+
+        // cw20 maker
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("maker"),
+            amount: Uint128::new(100),
+            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
+        let err = execute(
             deps.as_mut(),
             mock_env(),
             info,
-            ExecuteMsg::OpenOrder(msg.clone()),
+            ExecuteMsg::Receive(receive),
         )
-        .unwrap();
-        assert_eq!(0, res.messages.len());
-        assert_eq!(("method", "open_order"), res.attributes[0]);
-        assert_eq!(("order_id", "1"), res.attributes[1]);
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            ContractError::OrderInvalidReason(OrderInvalidReason::MultipleCw20Taker)
+        ));
+    }
 
-        // Close the open order
-        let receive = Cw20ReceiveMsg {
-            sender: String::from("target"),
-            amount: Uint128::new(12345),
-            msg: to_binary(&ExecuteMsg::CloseOrder { order_id: 1 }).unwrap(),
+    #[test]
+    fn query_simulate_open_reports_validity_and_price() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let maker_token = GenericBalance {
+            native: coins(100, "native"),
+            cw20: vec![],
         };
-        let info = mock_info(&cw20_token_contract, &[]);
-        let msg = ExecuteMsg::Receive(receive.clone());
-        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
-        assert_eq!(2, res.messages.len());
-        assert_eq!(("method", "close_order"), res.attributes[0]);
-        assert_eq!(("order_id", "1"), res.attributes[1]);
+        let msg = QueryMsg::SimulateOpen {
+            maker: String::from("maker"),
+            maker_token: maker_token.clone(),
+            msg: OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 200u128.into()),
+                target_address: Some(String::from("taker")),
+                expires_at_height: Some(mock_env().block.height + 10),
+                ..Default::default()
+            },
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let res: SimulateOpenResponse = from_binary(&res).unwrap();
+        assert_eq!(
+            SimulateOpenResponse {
+                valid: true,
+                reason: None,
+                implied_price: Some(Decimal::percent(200)),
+                would_expire_at: Some(mock_env().block.height + 10),
+            },
+            res
+        );
+
+        // An order expiring at the current height could never be closed
+        let msg = QueryMsg::SimulateOpen {
+            maker: String::from("maker"),
+            maker_token: maker_token.clone(),
+            msg: OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 200u128.into()),
+                expires_at_height: Some(mock_env().block.height),
+                ..Default::default()
+            },
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let res: SimulateOpenResponse = from_binary(&res).unwrap();
+        assert!(!res.valid);
+        assert_eq!(
+            Some(ContractError::OrderInvalidReason(OrderInvalidReason::ExpiryPassed).to_string()),
+            res.reason
+        );
+        assert_eq!(Some(mock_env().block.height), res.would_expire_at);
+
+        // The maker's balance is checked against the remainder like at open
+        let msg = QueryMsg::SimulateOpen {
+            maker: String::from("maker"),
+            maker_token: maker_token.clone(),
+            msg: OpenOrderMsg {
+                taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 200u128.into()),
+                remainder_from_allowance: Some(Cw20Coin {
+                    address: String::from("abc-token"),
+                    amount: Uint128::new(2_000_000),
+                }),
+                ..Default::default()
+            },
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let res: SimulateOpenResponse = from_binary(&res).unwrap();
+        assert_eq!(
+            Some(
+                ContractError::OrderInvalidReason(OrderInvalidReason::UnbackedRemainder)
+                    .to_string()
+            ),
+            res.reason
+        );
+
+        let msg = QueryMsg::SimulateOpen {
+            maker: String::from("maker"),
+            maker_token,
+            msg: OpenOrderMsg::default(),
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let res: SimulateOpenResponse = from_binary(&res).unwrap();
+        assert!(!res.valid);
+        assert_eq!(
+            Some(ContractError::OrderInvalidReason(OrderInvalidReason::EmptyTaker).to_string()),
+            res.reason
+        );
+        assert_eq!(None, res.implied_price);
+        assert_eq!(None, res.would_expire_at);
+        // Nothing was stored
+        assert_eq!(0, query_raw_order_count(deps.as_ref()).unwrap().count);
     }
 
     #[test]
-    fn close_order_with_invalid_token_fails() {
-        let mut deps = mock_dependencies(&[]);
+    fn order_meta_records_opening_block_and_tx() {
+        let mut deps = mock_deps();
         instantiate_contract(&mut deps);
 
-        let cw20_token_contract = String::from("my-cw20-token");
-        let cw20_token_amount = Uint128::new(12345);
-        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, cw20_token_amount);
+        let open = ExecuteMsg::OpenOrder(OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("xyz-token"), 100u128.into()),
+            ..Default::default()
+        });
+        let mut env = mock_env();
+        env.block.height = 4242;
+        env.transaction = Some(TransactionInfo { index: 7 });
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), env, info.clone(), open.clone()).unwrap();
+
+        let mut env = mock_env();
+        env.block.height = 4243;
+        env.transaction = None;
+        execute(deps.as_mut(), env, info, open).unwrap();
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::OrderMeta { id: 1 }).unwrap();
+        let meta: OrderMetaResponse = from_binary(&res).unwrap();
+        assert_eq!(
+            meta,
+            OrderMetaResponse {
+                block_height: 4242,
+                tx_index: Some(7),
+            }
+        );
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::OrderMeta { id: 2 }).unwrap();
+        let meta: OrderMetaResponse = from_binary(&res).unwrap();
+        assert_eq!(
+            meta,
+            OrderMetaResponse {
+                block_height: 4243,
+                tx_index: None,
+            }
+        );
+    }
+
+    const CW20_TOKENS: &[&str] = &["my-cw20-token", "xyz-token", "abc-token", "wrong-token"];
+
+    /// Mock dependencies whose querier answers TokenInfo, Balance and Allowance for the
+    /// known cw20 tokens
+    fn mock_deps() -> OwnedDeps<MockStorage, MockApi, MockQuerier, Empty> {
+        let mut deps = mock_dependencies(&[]);
+        deps.querier.update_wasm(mock_wasm(1_000_000));
+        deps
+    }
+
+    /// Wasm query handler where every address holds `holding` of each known cw20 token
+    /// and allows this contract to spend all of it
+    fn mock_wasm(holding: u128) -> impl Fn(&WasmQuery) -> QuerierResult {
+        move |query| match query {
+            // Prices every denom at 0.5 USD
+            WasmQuery::Smart { contract_addr, .. } if contract_addr == "oracle" => {
+                let price = OraclePriceResponse {
+                    price: Decimal::percent(50),
+                };
+                SystemResult::Ok(ContractResult::Ok(to_binary(&price).unwrap()))
+            }
+            WasmQuery::Smart { contract_addr, msg }
+                if CW20_TOKENS.contains(&contract_addr.as_str())
+                    && matches!(from_binary(msg), Ok(Cw20QueryMsg::Balance { .. })) =>
+            {
+                let balance = BalanceResponse {
+                    balance: Uint128::new(holding),
+                };
+                SystemResult::Ok(ContractResult::Ok(to_binary(&balance).unwrap()))
+            }
+            WasmQuery::Smart { contract_addr, msg }
+                if CW20_TOKENS.contains(&contract_addr.as_str())
+                    && matches!(from_binary(msg), Ok(Cw20QueryMsg::Allowance { .. })) =>
+            {
+                let allowance = AllowanceResponse {
+                    allowance: Uint128::new(holding),
+                    expires: Expiration::Never {},
+                };
+                SystemResult::Ok(ContractResult::Ok(to_binary(&allowance).unwrap()))
+            }
+            WasmQuery::Smart { contract_addr, .. }
+                if CW20_TOKENS.contains(&contract_addr.as_str()) =>
+            {
+                let info = TokenInfoResponse {
+                    name: contract_addr.clone(),
+                    symbol: String::from("TOKEN"),
+                    decimals: 6,
+                    total_supply: Uint128::new(1_000_000_000),
+                };
+                SystemResult::Ok(ContractResult::Ok(to_binary(&info).unwrap()))
+            }
+            _ => SystemResult::Err(SystemError::UnsupportedRequest {
+                kind: String::from("wasm"),
+            }),
+        }
+    }
 
+    #[test]
+    fn order_priced_beyond_decimal_range_opens_and_closes() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
+
+        let amount = Uint128::new(1_000_000_000_000_000_000_000);
         let msg = OpenOrderMsg {
-            taker_token: cw20_tokens.clone(),
-            target_address: None,
+            taker_token: create_cw20_tokens(&String::from("my-cw20-token"), amount),
+            ..Default::default()
         };
-        let maker = String::from("maker");
-        let balance = coins(100, "native");
-        let info = mock_info(&maker, &balance);
+        let info = mock_info("maker", &coins(1, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        let order = orders().load(&deps.storage, 1u64.into()).unwrap();
+        assert_eq!(None, order.implied_price(&[]));
+
+        let receive = Cw20ReceiveMsg {
+            sender: String::from("taker"),
+            amount,
+            msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                order_id: 1,
+                ..Default::default()
+            }))
+            .unwrap(),
+        };
+        let info = mock_info("my-cw20-token", &[]);
         let res = execute(
             deps.as_mut(),
             mock_env(),
             info,
-            ExecuteMsg::OpenOrder(msg.clone()),
+            ExecuteMsg::Receive(receive),
         )
         .unwrap();
-        assert_eq!(0, res.messages.len());
-        assert_eq!(("method", "open_order"), res.attributes[0]);
-        assert_eq!(("order_id", "1"), res.attributes[1]);
+        assert!(res.attributes.iter().all(|attr| attr.key != "exec_price"));
+    }
 
-        // Try to close the open order with a wrong token
-        let wrong_token_contract = String::from("wrong-token");
-        let wrong_token_amount = Uint128::new(12345);
+    #[test]
+    fn migrate_updates_older_version() {
+        let mut deps = mock_deps();
+        instantiate_contract(&mut deps);
 
-        let receive = Cw20ReceiveMsg {
-            sender: String::from("taker"),
-            amount: wrong_token_amount,
-            msg: to_binary(&ExecuteMsg::CloseOrder { order_id: 1 }).unwrap(),
-        };
-        let info = mock_info(&wrong_token_contract, &[]);
-        let msg = ExecuteMsg::Receive(receive.clone());
-        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
-        assert!(matches!(err, ContractError::OrderUnmatched {}));
+        set_contract_version(&mut deps.storage, CONTRACT_NAME, "0.0.1").unwrap();
+        let res = migrate(deps.as_mut(), mock_env(), MigrateMsg { owner: None }).unwrap();
+        assert_eq!(("from_version", "0.0.1"), res.attributes[1]);
+        let stored = get_contract_version(&deps.storage).unwrap();
+        assert_eq!(CONTRACT_VERSION, stored.version);
+
+        // Versions compare numerically, not as strings
+        set_contract_version(&mut deps.storage, CONTRACT_NAME, "10.0.0").unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), MigrateMsg { owner: None }).unwrap_err();
+        assert!(matches!(err, ContractError::Downgrade {}));
+
+        set_contract_version(&mut deps.storage, CONTRACT_NAME, "0.1.0-beta").unwrap();
+        migrate(deps.as_mut(), mock_env(), MigrateMsg { owner: None }).unwrap();
+
+        set_contract_version(&mut deps.storage, "crates.io:other", CONTRACT_VERSION).unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), MigrateMsg { owner: None }).unwrap_err();
+        assert!(matches!(err, ContractError::WrongContract {}));
     }
 
     #[test]
-    fn open_order_with_same_cw20_token_fails() {
-        let mut deps = mock_dependencies(&[]);
-        instantiate_contract(&mut deps);
+    fn parse_version_orders_pre_releases_first() {
+        let beta = parse_version("1.0.0-beta").unwrap();
+        assert!(beta < parse_version("1.0.0").unwrap());
+        assert!(beta > parse_version("0.9.10").unwrap());
+        assert!(parse_version("1.0.0-rc").unwrap() > beta);
+        parse_version("1.x").unwrap_err();
+    }
 
-        let cw20_token_contract = String::from("my-cw20-token");
-        let cw20_token_amount = Uint128::new(12345);
-        let cw20_tokens = create_cw20_tokens(&cw20_token_contract, cw20_token_amount);
+    #[test]
+    fn migrate_from_baseline_store() {
+        // The order shape and storage of the first version, which had no config
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct BaselineOrder {
+            maker_address: Addr,
+            maker_token: GenericBalance,
+            taker_token: GenericBalance,
+            target_address: Option<String>,
+            is_open: bool,
+        }
+        let baseline_orders: cw_storage_plus::Map<U64Key, BaselineOrder> =
+            cw_storage_plus::Map::new("orders");
 
-        let msg = OpenOrderMsg {
-            taker_token: cw20_tokens.clone(),
+        let mut deps = mock_deps();
+        set_contract_version(&mut deps.storage, CONTRACT_NAME, "0.0.1").unwrap();
+        let order = BaselineOrder {
+            maker_address: Addr::unchecked("maker"),
+            maker_token: GenericBalance {
+                native: coins(100, "native"),
+                cw20: vec![],
+            },
+            taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
             target_address: None,
+            is_open: true,
         };
-        let maker = String::from("maker");
-        let receive = Cw20ReceiveMsg {
-            sender: maker.clone(),
-            amount: cw20_token_amount,
-            msg: to_binary(&ExecuteMsg::OpenOrder(msg)).unwrap(),
+        baseline_orders
+            .save(&mut deps.storage, 1u64.into(), &order)
+            .unwrap();
+        ORDER_COUNT.save(&mut deps.storage, &1).unwrap();
+
+        let err = migrate(deps.as_mut(), mock_env(), MigrateMsg { owner: None }).unwrap_err();
+        assert!(matches!(err, ContractError::MissingOwner {}));
+        let msg = MigrateMsg {
+            owner: Some(String::from("owner")),
         };
-        let info = mock_info(&cw20_token_contract, &[]);
-        let msg = ExecuteMsg::Receive(receive.clone());
+        let res = migrate(deps.as_mut(), mock_env(), msg).unwrap();
+        assert_eq!(
+            Addr::unchecked("owner"),
+            CONFIG.load(&deps.storage).unwrap().owner
+        );
+        assert!(res.attributes.contains(&attr("backfill_pending", "true")));
+
+        // The pre-upgrade order is indexed by its maker once backfilled
+        let by_maker = |deps: Deps| {
+            let msg = QueryMsg::OrdersByMaker {
+                maker: String::from("maker"),
+                start_after: None,
+                limit: None,
+            };
+            let res: OrdersResponse = from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.orders
+                .iter()
+                .map(|order| order.id)
+                .collect::<Vec<u64>>()
+        };
+        assert!(by_maker(deps.as_ref()).is_empty());
+        let info = mock_info("anyone", &[]);
+        let msg = ExecuteMsg::BackfillOrders { limit: Some(1) };
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), msg.clone()).unwrap();
+        assert!(res.attributes.contains(&attr("done", "false")));
+        assert_eq!(vec![1], by_maker(deps.as_ref()));
+        // A full batch cannot tell the book ended, so one more call finishes it
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), msg.clone()).unwrap();
+        assert!(res.attributes.contains(&attr("backfilled", "0")));
+        assert!(res.attributes.contains(&attr("done", "true")));
         let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
-        assert!(matches!(err, ContractError::OrderInvalid(_msg)));
+        assert!(matches!(err, ContractError::NothingToBackfill {}));
+
+        // Opening and closing work again
+        let msg = OpenOrderMsg {
+            taker_token: create_cw20_tokens(&String::from("my-cw20-token"), 100u128.into()),
+            ..Default::default()
+        };
+        let info = mock_info("maker", &coins(100, "native"));
+        execute(deps.as_mut(), mock_env(), info, ExecuteMsg::OpenOrder(msg)).unwrap();
+        for order_id in [1, 2] {
+            let receive = Cw20ReceiveMsg {
+                sender: String::from("taker"),
+                amount: Uint128::new(100),
+                msg: to_binary(&ExecuteMsg::CloseOrder(CloseOrderMsg {
+                    order_id,
+                    ..Default::default()
+                }))
+                .unwrap(),
+            };
+            let info = mock_info("my-cw20-token", &[]);
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                info,
+                ExecuteMsg::Receive(receive),
+            )
+            .unwrap();
+        }
     }
 
     fn instantiate_contract(deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier, Empty>) {
-        let msg = InstantiateMsg {};
+        let msg = InstantiateMsg {
+            open_cooldown_blocks: None,
+            denom_aliases: None,
+            asset_decimals: None,
+        };
         let info = mock_info("anyone", &[]);
         let res = instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
         assert_eq!(0, res.messages.len());
@@ -9,20 +9,128 @@ pub enum ContractError {
     #[error("Unauthorized")]
     Unauthorized {},
 
+    #[error("Sender is not a cw20 token contract")]
+    InvalidCw20 {},
+
     #[error("Send some coins to create an order")]
     EmptyBalance {},
 
-    #[error("Order taker information is invalid")]
-    OrderInvalid(String),
+    #[error("Order is invalid: {0}")]
+    OrderInvalidReason(OrderInvalidReason),
 
     #[error("Order is already closed")]
     OrderClosed {},
 
+    #[error("Order has expired")]
+    OrderExpired {},
+
     #[error("Order is reserved for a specific address")]
     OrderReserved {},
 
     #[error("Order is not matched")]
     OrderUnmatched {},
+
+    #[error("Taker acknowledgement does not match the order")]
+    AckMismatch {},
+
+    #[error("Maker must wait for the cooldown before opening another order")]
+    Cooldown {},
+
+    #[error("No pending deposit to reclaim")]
+    NoPendingDeposit {},
+
+    #[error("No stranded cw20 to claim")]
+    NothingStranded {},
+
+    #[error("A config field cannot be both set and cleared")]
+    ConflictingConfigUpdate {},
+
+    #[error("No orders are left to backfill")]
+    NothingToBackfill {},
+
+    #[error("Split must leave a non-zero amount of every token in both orders")]
+    InvalidSplit {},
+
+    #[error("Orders pulling from the maker's allowance cannot be transferred")]
+    NotTransferable {},
+
+    #[error("Withdrawal must take out and leave a non-zero amount of every token")]
+    InvalidWithdrawal {},
+
+    #[error("Price oracle is not configured or did not return a price")]
+    OracleUnavailable {},
+
+    #[error("Oracle price puts the demanded amount out of range")]
+    UsdDemandOutOfRange {},
+
+    #[error("Order maker token holds no cw20 token")]
+    NoCw20Maker {},
+
+    #[error("Old and new cw20 contracts must differ")]
+    SameCw20Contract {},
+
+    #[error("Order price is above the taker's max price")]
+    SlippageExceeded {},
+
+    #[error("Order has changed since the taker last saw it")]
+    StaleOrder {},
+
+    #[error("Maker still holds enough to cover the order")]
+    OrderBacked {},
+
+    #[error("Cannot migrate from a different contract")]
+    WrongContract {},
+
+    #[error("Cannot migrate from a newer contract version")]
+    Downgrade {},
+
+    #[error("Migrating a contract without a config needs an owner")]
+    MissingOwner {},
     // Add any other custom errors you like here.
     // Look at https://docs.rs/thiserror/1.0.21/thiserror/ for details.
 }
+
+#[derive(Error, Debug, PartialEq)]
+pub enum OrderInvalidReason {
+    #[error("At least one native/cw20 token should be specified as a taker")]
+    EmptyTaker,
+
+    #[error("Only one cw20 token can be specified as a taker")]
+    MultipleCw20Taker,
+
+    #[error("Only one native or cw20 token can be specified as a taker")]
+    MixedTaker,
+
+    #[error("Maker and taker tokens cannot both be native tokens")]
+    BothNative,
+
+    #[error("Maker and taker tokens cannot be the same cw20 tokens")]
+    MakerTakerSameToken,
+
+    #[error("Deposited maker tokens do not match the expected maker tokens")]
+    MakerTokenMismatch,
+
+    #[error("Gift orders need a target address and no taker tokens")]
+    InvalidGift,
+
+    #[error("Orders priced in USD need a single taker token")]
+    UsdPricedTaker,
+
+    #[error("The maker amount pulled from the allowance must be non-zero")]
+    ZeroAllowanceRemainder,
+
+    #[error("The maker's balance or allowance does not cover the remainder")]
+    UnbackedRemainder,
+
+    #[error("Order price is below the minimum price of its pair")]
+    BelowMinPrice,
+
+    #[error("The expiry height has already been reached")]
+    ExpiryPassed,
+
+    #[error("Every maker token amount must be non-zero")]
+    ZeroMakerAmount,
+
+    #[error("Every taker token amount must be non-zero")]
+    ZeroTakerAmount,
+}
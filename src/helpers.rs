@@ -0,0 +1,40 @@
+use cosmwasm_std::{Addr, Api};
+
+use crate::error::ContractError;
+
+/// Validates every address in the list, failing on the first invalid one
+pub fn validate_addrs(api: &dyn Api, addrs: &[String]) -> Result<Vec<Addr>, ContractError> {
+    addrs
+        .iter()
+        .map(|addr| Ok(api.addr_validate(addr)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::mock_dependencies;
+
+    #[test]
+    fn validate_addrs_accepts_valid_list() {
+        let deps = mock_dependencies(&[]);
+        let addrs = vec![String::from("alice"), String::from("bob")];
+        let validated = validate_addrs(&deps.api, &addrs).unwrap();
+        assert_eq!(
+            vec![Addr::unchecked("alice"), Addr::unchecked("bob")],
+            validated
+        );
+    }
+
+    #[test]
+    fn validate_addrs_rejects_invalid_entry() {
+        let deps = mock_dependencies(&[]);
+        let addrs = vec![
+            String::from("alice"),
+            String::from("x"),
+            String::from("bob"),
+        ];
+        let err = validate_addrs(&deps.api, &addrs).unwrap_err();
+        assert!(matches!(err, ContractError::Std(_)));
+    }
+}
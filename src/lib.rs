@@ -1,6 +1,7 @@
 pub mod contract;
 mod error;
+mod helpers;
 pub mod msg;
 pub mod state;
 
-pub use crate::error::ContractError;
+pub use crate::error::{ContractError, OrderInvalidReason};
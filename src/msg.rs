@@ -1,49 +1,538 @@
-use crate::state::GenericBalance;
-use cosmwasm_std::Addr;
+use crate::state::{AssetRef, GenericBalance};
+use cosmwasm_std::{Addr, Coin, Decimal, Uint128};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cw20::Cw20ReceiveMsg;
+use cw20::{Cw20Coin, Cw20CoinVerified, Cw20ReceiveMsg};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
-pub struct InstantiateMsg {}
+pub struct InstantiateMsg {
+    /// Minimum number of blocks a maker must wait between opening orders
+    pub open_cooldown_blocks: Option<u64>,
+    /// Pairs of assets a taker may pay interchangeably, e.g. a native denom and its
+    /// cw20 wrapper
+    pub denom_aliases: Option<Vec<(AssetRef, AssetRef)>>,
+    /// Decimals of native denoms or cw20 addresses, used to display prices
+    pub asset_decimals: Option<Vec<(String, u8)>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MigrateMsg {
+    /// Owner of the config created when migrating from a version that had none
+    pub owner: Option<String>,
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ExecuteMsg {
     OpenOrder(OpenOrderMsg),
-    CloseOrder {
+    CloseOrder(CloseOrderMsg),
+    /// Closes several native-demand orders at once. The attached funds must equal
+    /// the sum of their taker tokens.
+    CloseOrders {
+        order_ids: Vec<u64>,
+    },
+    /// Lets the maker close an open order and take back the escrowed maker token
+    CancelOrder {
+        order_id: u64,
+    },
+    /// Cancels every open order of the sender tagged with the group id
+    CancelGroup {
+        group_id: String,
+    },
+    /// Closes an order pulling part of its maker token from an allowance when the maker's
+    /// balance of that cw20 or allowance to this contract no longer covers it. The
+    /// escrowed bond is forfeited to the caller. Anyone can call this.
+    InvalidateUnbacked {
+        order_id: u64,
+    },
+    /// Lets the target of a reserved order decline it, refunding the maker
+    DeclineOrder {
+        order_id: u64,
+    },
+    /// Lets the maker drop the target of a reserved order so anyone can close it
+    MakePublic {
+        order_id: u64,
+    },
+    /// Lets the maker split an order in two at the same price. The original order keeps
+    /// `first_fraction_bps` of both tokens and a new order gets the rest.
+    SplitOrder {
         order_id: u64,
+        first_fraction_bps: u16,
+    },
+    /// Lets a maker replace the taker token of several of their open orders. Fails as a
+    /// whole if any of the orders cannot be updated.
+    UpdateOrders {
+        updates: Vec<(u64, GenericBalance)>,
+    },
+    /// Lets the maker take `bps` basis points of the escrow back, shrinking the taker
+    /// demand by the same fraction so the price is unchanged
+    WithdrawFraction {
+        order_id: u64,
+        bps: u16,
+    },
+    /// Lets the maker hand an open order, and the right to its proceeds, to another address.
+    /// The maker callback and vault are the previous maker's, so they are cleared.
+    TransferOrder {
+        order_id: u64,
+        new_maker: String,
+    },
+    /// Closes an order whose maker cw20 can no longer be transferred, leaving that cw20
+    /// in the contract for the maker to claim later. Only the owner can call this.
+    ForceCloseBroken {
+        order_id: u64,
+    },
+    /// Sends the sender the cw20 its force-closed orders left in the contract
+    ClaimStranded {
+        cw20_address: String,
+    },
+    /// Returns the cw20 deposits the sender accumulated toward an order
+    ReclaimDeposit {
+        order_id: u64,
+    },
+    /// Updates the provided config fields, leaving the others unchanged. Only the owner can
+    /// call this.
+    UpdateConfig {
+        owner: Option<String>,
+        open_cooldown_blocks: Option<u64>,
+        /// Removes the open cooldown. Cannot be combined with `open_cooldown_blocks`.
+        #[serde(default)]
+        clear_open_cooldown: bool,
+        denom_aliases: Option<Vec<(AssetRef, AssetRef)>>,
+        asset_decimals: Option<Vec<(String, u8)>>,
+        /// Replaces the list of addresses that may not close orders
+        blacklist: Option<Vec<String>>,
+        oracle: Option<String>,
+        /// Removes the oracle, disabling USD-priced orders. Cannot be combined with `oracle`.
+        #[serde(default)]
+        clear_oracle: bool,
+        /// Replaces the price floors, given as (maker denom, taker denom, min price)
+        min_prices: Option<Vec<(String, String, Decimal)>>,
+    },
+    /// Rewrites a migrated cw20 contract address in the tokens and allowance remainder of
+    /// every order, in their pending deposits and in the cw20 stranded by force closes.
+    /// Only the owner can call this.
+    RemapCw20 {
+        old_contract: String,
+        new_contract: String,
+    },
+    /// Saves up to `limit` more of the orders stored before the latest migrate again, so
+    /// indexes added since cover them. Anyone can call this until every order was.
+    BackfillOrders {
+        limit: Option<u32>,
     },
     /// This accepts a properly-encoded ReceiveMsg from a cw20 contract
     Receive(Cw20ReceiveMsg),
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
 #[serde(rename_all = "snake_case")]
 pub struct OpenOrderMsg {
     pub taker_token: GenericBalance,
     pub target_address: Option<String>,
+    /// Free-form note stored with the order, e.g. an OTC reference
+    pub memo: Option<String>,
+    /// Nonce the taker must echo back as `ack` when closing the order
+    pub require_taker_ack: Option<String>,
+    /// When set, the deposited maker balance must equal it exactly
+    pub expected_maker_token: Option<GenericBalance>,
+    /// Contract that is sent a MakerCallbackMsg when the order is filled
+    pub maker_callback: Option<String>,
+    /// Opens a gift the target claims by closing with no funds. Requires a target address
+    /// and an empty taker token.
+    #[serde(default)]
+    pub is_gift: bool,
+    /// Accept cw20 payments above the demanded amount and refund the surplus, instead of
+    /// rejecting them. Native overpayments are always refunded.
+    #[serde(default)]
+    pub refund_overpayment: bool,
+    /// Demand this much USD, in the oracle's units, worth of the taker token instead of
+    /// its amount. The amount is resolved through the config oracle at close.
+    pub price_in_usd: Option<Uint128>,
+    /// Escrow only the deposited funds as a bond and pull this much more of a cw20 from
+    /// the maker's allowance when the order is closed. The maker's balance and allowance
+    /// must cover it at open, and the close fails if they no longer do.
+    pub remainder_from_allowance: Option<Cw20Coin>,
+    /// Vault contract the maker's proceeds are deposited into, crediting the maker,
+    /// instead of being sent to the maker
+    pub deposit_to_vault: Option<String>,
+    /// Tag shared by related orders of the maker, so they can be cancelled together
+    pub group_id: Option<String>,
+    /// Block height from which the order can no longer be closed. Must be above the
+    /// current height.
+    pub expires_at_height: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct CloseOrderMsg {
+    pub order_id: u64,
+    /// Must match the order's `require_taker_ack` when one is set
+    pub ack: Option<String>,
+    /// Contract that is sent a TakerCallbackMsg after the tokens are transferred
+    pub taker_callback: Option<String>,
+    /// Highest implied price (taker per maker unit) the taker accepts. Orders without a
+    /// single-asset price cannot be closed with a max price.
+    pub max_price: Option<Decimal>,
+    /// Order `hash` the taker expects, so a close fails if the order changed since
+    pub expected_hash: Option<String>,
 }
 
+// Like ReceiveMsg, the simulated open is left unboxed
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
-    Order { id: u64 },
+    Order {
+        id: u64,
+    },
+    /// Runs the checks an open of `maker_token` by the maker would go through, without
+    /// opening it
+    SimulateOpen {
+        maker: String,
+        maker_token: GenericBalance,
+        msg: OpenOrderMsg,
+    },
+    /// Returns the block height and transaction index the order was opened at
+    OrderMeta {
+        id: u64,
+    },
+    /// Returns the funds a taker must attach to close the order with native tokens
+    CloseFunds {
+        order_id: u64,
+    },
+    /// Lists the transfers a full close of the order would make. Overpayment and
+    /// pending deposit refunds are left out.
+    SettlementPreview {
+        order_id: u64,
+    },
+    /// Lists the maker's orders tagged with the group id, in ascending id order
+    OrdersByGroup {
+        maker: String,
+        group_id: String,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Returns the deduplicated set of assets offered or demanded by open orders among
+    /// a bounded scan of the book. Merge the pages until `next_start_after` is empty.
+    ActiveAssets {
+        start_after: Option<u64>,
+    },
+    /// Tells whether an order must be closed with native funds or a cw20 Send
+    AssetKind {
+        order_id: u64,
+    },
+    /// Returns the open single-asset order for the pair with the lowest taker-per-maker
+    /// price. Denoms are native denoms or cw20 contract addresses. Only a bounded scan of
+    /// the book is searched; the best order overall is the best of every page up to an
+    /// empty `next_start_after`.
+    BestOrder {
+        maker_denom: String,
+        taker_denom: String,
+        start_after: Option<u64>,
+    },
+    /// Sums the maker amounts of open single-asset orders of the pair at each implied
+    /// price, returning up to `levels` levels starting from the lowest price. Levels
+    /// cover a bounded scan of the book; add up the pages to `next_start_after` being
+    /// empty for the whole book.
+    Depth {
+        base_denom: String,
+        taker_denom: String,
+        levels: u32,
+        start_after: Option<u64>,
+    },
+    /// Lists orders matching all of the provided filters, in ascending id order. The
+    /// amount bounds apply to the maker token of single-asset orders. Each call scans a
+    /// bounded number of orders; continue from `next_start_after` until it is empty.
+    /// `open` matches orders a taker can still close, so expired orders count as not open.
+    Search {
+        maker: Option<String>,
+        taker_denom: Option<String>,
+        open: Option<bool>,
+        min_amount: Option<Uint128>,
+        max_amount: Option<Uint128>,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Lists orders oldest first, paginated by the id to start after
+    ListOrders {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Lists the orders opened by or transferred to the maker, in ascending id order
+    OrdersByMaker {
+        maker: String,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Lists orders newest first, paginated by the id to start before
+    ListOrdersDesc {
+        start_before: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Lists open orders whose target address no longer passes validation, e.g. ones
+    /// stored before targets were validated at open. Scans a bounded number of orders
+    /// per call like Search.
+    OrdersWithInvalidTarget {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Tells whether the address is barred from closing orders
+    IsBlacklisted {
+        address: String,
+    },
+    /// Counts the open orders whose taker token the balance covers on its own, within a
+    /// bounded scan of the book. Sum the counts until `next_start_after` is empty.
+    FillableCount {
+        balance: GenericBalance,
+        start_after: Option<u64>,
+    },
+    /// Returns order counts, traded volume and the number of active assets in one call.
+    /// Counts and active assets cover a bounded scan of the book starting after
+    /// `start_after`, while the volume is always the total.
+    Stats {
+        start_after: Option<u64>,
+    },
+    /// Tells which optional features this deployment supports and has enabled
+    Capabilities {},
+    /// Returns the raw ORDER_COUNT value, which is the id of the latest opened order
+    RawOrderCount {},
+    /// Returns the time-weighted average price of the closes of base (maker) orders
+    /// paid in quote (taker) over the last `window_seconds`
+    Twap {
+        base: String,
+        quote: String,
+        window_seconds: u64,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct OrderResponse {
+    pub id: u64,
     pub maker_address: Addr,
     pub maker_token: GenericBalance,
     pub taker_token: GenericBalance,
-    pub target_address: Option<String>,
+    pub target_address: Option<Addr>,
     pub is_open: bool,
+    pub memo: Option<String>,
+    /// Block height from which the order can no longer be closed
+    pub expires_at_height: Option<u64>,
+    /// USD the order demands, in the oracle's units. The taker token is then only the
+    /// asset paid, its amount being resolved at close.
+    pub price_in_usd: Option<Uint128>,
+    /// Maker cw20 pulled from the maker's allowance at close, on top of the maker token
+    pub remainder_from_allowance: Option<Cw20CoinVerified>,
+    pub group_id: Option<String>,
+    /// Nonce the taker must echo back as `ack` when closing the order
+    pub require_taker_ack: Option<String>,
+    /// Vault the maker's proceeds are deposited into
+    pub deposit_to_vault: Option<Addr>,
+    /// Changes whenever the stored order does
+    pub hash: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SimulateOpenResponse {
+    pub valid: bool,
+    /// Error the open would fail with
+    pub reason: Option<String>,
+    pub implied_price: Option<Decimal>,
+    /// Block height from which the order could no longer be closed
+    pub would_expire_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CloseFundsResponse {
+    /// Coins to attach as the funds of CloseOrder, empty for cw20 demands
+    pub funds: Vec<Coin>,
+    /// The order demands a cw20 token, which must be sent with a cw20 Send instead
+    pub cw20: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SettlementPreviewResponse {
+    pub transfers: Vec<SettlementTransfer>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SettlementTransfer {
+    pub recipient: SettlementRecipient,
+    /// Native denom or cw20 address
+    pub asset: String,
+    pub amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SettlementRecipient {
+    Maker {
+        address: Addr,
+    },
+    /// Vault the maker's proceeds are deposited into on the maker's behalf
+    Vault {
+        address: Addr,
+    },
+    /// Whoever closes the order
+    Taker {},
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OrderMetaResponse {
+    pub block_height: u64,
+    pub tx_index: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OrdersResponse {
+    pub orders: Vec<OrderResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SearchResponse {
+    pub orders: Vec<OrderResponse>,
+    /// Id of the last order scanned, set when the scan stopped before the end of the book
+    pub next_start_after: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BestOrderResponse {
+    pub order: Option<OrderResponse>,
+    /// Id of the last order scanned, set when the scan stopped before the end of the book
+    pub next_start_after: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DepthResponse {
+    pub levels: Vec<DepthLevel>,
+    /// Id of the last order scanned, set when the scan stopped before the end of the book
+    pub next_start_after: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DepthLevel {
+    pub price: Decimal,
+    pub maker_amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct IsBlacklistedResponse {
+    pub blacklisted: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FillableCountResponse {
+    pub count: u64,
+    /// Id of the last order scanned, set when the scan stopped before the end of the book
+    pub next_start_after: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StatsResponse {
+    pub total_orders: u64,
+    /// Open orders that have not expired
+    pub open_orders: u64,
+    /// Orders never closed whose expiry height has been reached
+    pub expired_orders: u64,
+    pub closed_orders: u64,
+    /// Traded amount per native denom or cw20 address, counting both sides of each fill
+    pub volume: Vec<(String, Uint128)>,
+    /// Number of distinct native denoms and cw20 contracts in open orders
+    pub active_assets: u64,
+    /// Id of the last order scanned, set when the scan stopped before the end of the book
+    pub next_start_after: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CapabilitiesResponse {
+    /// Not supported, orders are always filled in full
+    pub partial_fills: bool,
+    /// Not supported, only native and cw20 tokens can be escrowed
+    pub nft_support: bool,
+    /// Not supported, fills are not charged
+    pub fees: bool,
+    /// Not supported, the contract cannot be paused
+    pub pause: bool,
+    pub open_cooldown: bool,
+    pub denom_aliases: bool,
+    pub blacklist: bool,
+    pub usd_pricing: bool,
+    pub min_prices: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct RawOrderCountResponse {
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TwapResponse {
+    /// Quote amount per base unit
+    pub price: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ActiveAssetsResponse {
+    pub native_denoms: Vec<String>,
+    pub cw20_contracts: Vec<Addr>,
+    /// Id of the last order scanned, set when the scan stopped before the end of the book
+    pub next_start_after: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AssetKindResponse {
+    Native { denoms: Vec<String> },
+    Cw20 { contract: Addr },
+}
+
+/// Sent to an order's maker_callback contract. A failing callback does not revert the fill.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MakerCallbackMsg {
+    OrderFilled {
+        order_id: u64,
+        taker_address: Addr,
+        taker_token: GenericBalance,
+    },
+}
+
+/// Sent to the taker_callback contract of a close once the order has settled
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TakerCallbackMsg {
+    Filled {
+        order_id: u64,
+        maker_token: GenericBalance,
+    },
+}
+
+/// Deposits the attached funds into a vault on behalf of `recipient`. Sent as the
+/// message of a vault execute for native proceeds and of a cw20 Send for cw20 proceeds.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum VaultMsg {
+    Deposit { recipient: String },
+}
+
+/// Query sent to the config oracle to price USD-denominated demands
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OracleQueryMsg {
+    /// Native denom or cw20 contract address to price
+    Price { denom: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OraclePriceResponse {
+    /// USD per unit of the denom
+    pub price: Decimal,
 }
 
+// Messages are deserialized once per call, so boxing the open variant buys nothing
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ReceiveMsg {
     OpenOrder(OpenOrderMsg),
-    CloseOrder { order_id: u64 },
+    CloseOrder(CloseOrderMsg),
 }
@@ -1,8 +1,10 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cosmwasm_std::{Addr, Coin, StdResult, Storage};
-use cw_storage_plus::{Item, Map, U64Key};
+use cosmwasm_std::{to_vec, Addr, Coin, Decimal, StdError, StdResult, Storage, Uint128};
+use cw_storage_plus::{Bound, Index, IndexList, IndexedMap, Item, Map, MultiIndex, U64Key};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
 
 use cw20::{Balance, Cw20CoinVerified};
 
@@ -45,6 +47,143 @@ impl GenericBalance {
             }
         };
     }
+
+    /// Sums repeated native denoms and cw20 addresses into a single entry each
+    pub fn merged(&self) -> GenericBalance {
+        let mut balance = GenericBalance::default();
+        balance.add_tokens(Balance::from(self.native.clone()));
+        for token in &self.cw20 {
+            balance.add_tokens(Balance::Cw20(token.clone()));
+        }
+        balance
+    }
+
+    /// Compares the balances regardless of the order their tokens are listed in
+    pub fn matches(&self, other: &GenericBalance) -> bool {
+        self.sorted() == other.sorted()
+    }
+
+    fn sorted(&self) -> GenericBalance {
+        let mut balance = self.clone();
+        balance.native.sort_by(|a, b| a.denom.cmp(&b.denom));
+        balance.cw20.sort_by(|a, b| a.address.cmp(&b.address));
+        balance
+    }
+
+    /// Rewrites every asset aliased to another one into the first asset of its pair
+    pub fn canonicalize(&self, aliases: &[(AssetRef, AssetRef)]) -> GenericBalance {
+        let mut balance = GenericBalance::default();
+        for coin in &self.native {
+            balance.add_asset(
+                AssetRef::Native(coin.denom.clone()).canonical(aliases),
+                coin.amount,
+            );
+        }
+        for token in &self.cw20 {
+            balance.add_asset(
+                AssetRef::Cw20(token.address.clone()).canonical(aliases),
+                token.amount,
+            );
+        }
+        balance
+    }
+
+    fn add_asset(&mut self, asset: AssetRef, amount: Uint128) {
+        match asset {
+            AssetRef::Native(denom) => self.add_tokens(Balance::from(vec![Coin { denom, amount }])),
+            AssetRef::Cw20(address) => {
+                self.add_tokens(Balance::Cw20(Cw20CoinVerified { address, amount }))
+            }
+        }
+    }
+
+    /// Splits off `bps` basis points of every token, returning (split off, remainder).
+    /// Callers must keep `bps` at most 10_000.
+    pub fn split(&self, bps: u16) -> (GenericBalance, GenericBalance) {
+        let mut first = self.clone();
+        let mut second = self.clone();
+        for (coin, rest) in first.native.iter_mut().zip(second.native.iter_mut()) {
+            coin.amount = coin.amount.multiply_ratio(bps, 10_000u128);
+            rest.amount -= coin.amount;
+        }
+        for (token, rest) in first.cw20.iter_mut().zip(second.cw20.iter_mut()) {
+            token.amount = token.amount.multiply_ratio(bps, 10_000u128);
+            rest.amount -= token.amount;
+        }
+        (first, second)
+    }
+
+    /// Returns what is left after taking `other` out of this balance, or None when this
+    /// balance does not hold every token of `other` in at least the same amount
+    pub fn checked_sub(&self, other: &GenericBalance) -> Option<GenericBalance> {
+        let mut rest = self.clone();
+        for coin in &other.native {
+            let held = rest
+                .native
+                .iter_mut()
+                .find(|held| held.denom == coin.denom)?;
+            held.amount = held.amount.checked_sub(coin.amount).ok()?;
+        }
+        for token in &other.cw20 {
+            let held = rest
+                .cw20
+                .iter_mut()
+                .find(|held| held.address == token.address)?;
+            held.amount = held.amount.checked_sub(token.amount).ok()?;
+        }
+        rest.native.retain(|coin| !coin.amount.is_zero());
+        rest.cw20.retain(|token| !token.amount.is_zero());
+        Some(rest)
+    }
+
+    /// True when any of the tokens has a zero amount
+    pub fn has_zero_amount(&self) -> bool {
+        self.native.iter().any(|coin| coin.amount.is_zero())
+            || self.cw20.iter().any(|token| token.amount.is_zero())
+    }
+
+    /// Returns the denom (or cw20 contract address) and amount when the balance holds
+    /// exactly one native or cw20 token
+    pub fn single_asset(&self) -> Option<(&str, Uint128)> {
+        match (self.native.as_slice(), self.cw20.as_slice()) {
+            ([coin], []) => Some((coin.denom.as_str(), coin.amount)),
+            ([], [token]) => Some((token.address.as_str(), token.amount)),
+            _ => None,
+        }
+    }
+}
+
+impl TryFrom<&GenericBalance> for Balance {
+    type Error = StdError;
+
+    /// Succeeds when the balance holds only native tokens or a single cw20 token
+    fn try_from(balance: &GenericBalance) -> StdResult<Self> {
+        match balance.cw20.as_slice() {
+            [] => Ok(Balance::from(balance.native.clone())),
+            [token] if balance.native.is_empty() => Ok(Balance::Cw20(token.clone())),
+            _ => Err(StdError::generic_err(
+                "Balance mixes native and cw20 tokens or holds several cw20 tokens",
+            )),
+        }
+    }
+}
+
+/// Identifies a native denom or a cw20 contract, regardless of amount
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AssetRef {
+    Native(String),
+    Cw20(Addr),
+}
+
+impl AssetRef {
+    /// The first asset of the alias pair this asset belongs to, or the asset itself
+    pub fn canonical(self, aliases: &[(AssetRef, AssetRef)]) -> AssetRef {
+        aliases
+            .iter()
+            .find(|(_, alias)| *alias == self)
+            .map_or(self, |(canonical, _)| canonical.clone())
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
@@ -52,12 +191,271 @@ pub struct Order {
     pub maker_address: Addr,
     pub maker_token: GenericBalance,
     pub taker_token: GenericBalance,
-    pub target_address: Option<String>,
+    pub target_address: Option<Addr>,
     pub is_open: bool,
+    pub memo: Option<String>,
+    pub require_taker_ack: Option<String>,
+    pub maker_callback: Option<Addr>,
+    #[serde(default)]
+    pub refund_overpayment: bool,
+    #[serde(default)]
+    pub price_in_usd: Option<Uint128>,
+    /// Maker cw20 pulled from the maker's allowance at close, on top of the escrowed
+    /// maker token
+    #[serde(default)]
+    pub remainder_from_allowance: Option<Cw20CoinVerified>,
+    /// Receives the maker's proceeds on the maker's behalf
+    #[serde(default)]
+    pub deposit_to_vault: Option<Addr>,
+    #[serde(default)]
+    pub group_id: Option<String>,
+    #[serde(default)]
+    pub expires_at_height: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct Config {
+    pub owner: Addr,
+    pub open_cooldown_blocks: Option<u64>,
+    /// Pairs of assets a taker may pay interchangeably, the first being canonical
+    #[serde(default)]
+    pub denom_aliases: Vec<(AssetRef, AssetRef)>,
+    /// Decimals of native denoms or cw20 addresses, used to display prices
+    #[serde(default)]
+    pub asset_decimals: Vec<(String, u8)>,
+    /// Addresses that may not close orders
+    #[serde(default)]
+    pub blacklist: Vec<Addr>,
+    /// Prices the taker token of orders opened with a USD demand
+    #[serde(default)]
+    pub oracle: Option<Addr>,
+    /// Lowest implied price an order may open at, per (maker denom, taker denom) pair
+    #[serde(default)]
+    pub min_prices: Vec<(String, String, Decimal)>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct PendingDeposit {
+    pub taker_address: Addr,
+    pub token: Cw20CoinVerified,
 }
 
-pub const ORDERS: Map<U64Key, Order> = Map::new("orders");
+impl Order {
+    /// Hex SHA-256 of the order's JSON serialization, which changes whenever any field
+    /// of the order does
+    pub fn hash(&self) -> StdResult<String> {
+        let digest = Sha256::digest(&to_vec(self)?);
+        Ok(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    /// Everything the taker receives at close: the escrowed maker token plus the
+    /// remainder pulled from the maker's allowance
+    pub fn offered_token(&self) -> GenericBalance {
+        let mut offered = self.maker_token.clone();
+        if let Some(remainder) = &self.remainder_from_allowance {
+            offered.add_tokens(Balance::Cw20(remainder.clone()));
+        }
+        offered
+    }
+
+    /// Whether the order can no longer be closed at the block height
+    pub fn is_expired(&self, height: u64) -> bool {
+        matches!(self.expires_at_height, Some(expiry) if height >= expiry)
+    }
+
+    /// Whether a taker can still close the order at the block height
+    pub fn is_live(&self, height: u64) -> bool {
+        self.is_open && !self.is_expired(height)
+    }
+
+    /// Taker amount per offered unit, only defined for single-asset orders. Amounts are
+    /// divided by 10^decimals for assets with a decimals hint.
+    pub fn implied_price(&self, asset_decimals: &[(String, u8)]) -> Option<Decimal> {
+        implied_price(&self.offered_token(), &self.taker_token, asset_decimals)
+    }
+}
+
+/// Price of an order offering `maker_token` for `taker_token`, see Order::implied_price
+pub fn implied_price(
+    maker_token: &GenericBalance,
+    taker_token: &GenericBalance,
+    asset_decimals: &[(String, u8)],
+) -> Option<Decimal> {
+    let (maker, maker_amount) = maker_token.single_asset()?;
+    let (taker, taker_amount) = taker_token.single_asset()?;
+    let unit = |denom: &str| {
+        let decimals = asset_decimals
+            .iter()
+            .find(|(hinted, _)| hinted == denom)
+            .map_or(0, |(_, decimals)| *decimals);
+        10u128.checked_pow(decimals.into()).map(Uint128::new)
+    };
+    let numerator = taker_amount.checked_mul(unit(maker)?).ok()?;
+    let denominator = maker_amount.checked_mul(unit(taker)?).ok()?;
+    checked_ratio(numerator, denominator)
+}
+
+/// Where an order was opened, kept for audit trails
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct OrderMeta {
+    pub block_height: u64,
+    /// Index of the opening transaction within its block, when the chain provides it
+    pub tx_index: Option<u32>,
+}
+
+/// Cumulative price of a pair as of `time`, and the price that applied from then on
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct TwapObservation {
+    pub time: u64,
+    pub cumulative: Decimal,
+    pub taker_amount: Uint128,
+    pub maker_amount: Uint128,
+}
+
+impl TwapObservation {
+    /// Cumulative price extended to `time` at the observed price, failing when it no
+    /// longer fits a Decimal
+    pub fn cumulative_at(&self, time: u64) -> StdResult<Decimal> {
+        let elapsed = Uint128::from(time - self.time);
+        let added = checked_ratio(self.taker_amount.checked_mul(elapsed)?, self.maker_amount)
+            .filter(|added| *added <= Decimal::MAX - self.cumulative)
+            .ok_or_else(|| StdError::generic_err("Cumulative price overflow"))?;
+        Ok(self.cumulative + added)
+    }
+}
+
+/// `numerator / denominator` as a Decimal, or None when the denominator is zero or the
+/// ratio is too large to represent
+pub fn checked_ratio(numerator: Uint128, denominator: Uint128) -> Option<Decimal> {
+    const FRACTIONAL: u128 = 1_000_000_000_000_000_000;
+    if denominator.is_zero() {
+        return None;
+    }
+    let (numerator, denominator) = (numerator.u128(), denominator.u128());
+    let whole = (numerator / denominator).checked_mul(FRACTIONAL)?;
+    // Below FRACTIONAL, as the remainder is below the denominator
+    let fraction = Uint128::new(numerator % denominator).multiply_ratio(FRACTIONAL, denominator);
+    let atomics = Uint128::new(whole).checked_add(fraction).ok()?;
+    Some(Decimal::from_ratio(atomics, FRACTIONAL))
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug, Default)]
+pub struct TwapState {
+    /// Oldest first, capped at MAX_TWAP_OBSERVATIONS
+    pub observations: Vec<TwapObservation>,
+}
+
+pub const MAX_TWAP_OBSERVATIONS: usize = 64;
+
+pub const CONFIG: Item<Config> = Item::new("config");
+pub const ORDER_META: Map<U64Key, OrderMeta> = Map::new("order_meta");
 pub const ORDER_COUNT: Item<u64> = Item::new("order_count");
+/// Id of the last order re-saved since the latest migrate, until every order was
+pub const BACKFILL_CURSOR: Item<u64> = Item::new("backfill_cursor");
+/// cw20 deposits accumulated toward closing an order, keyed by (order_id, taker, cw20 contract)
+pub const PENDING_DEPOSITS: Map<(U64Key, &Addr, &Addr), PendingDeposit> =
+    Map::new("pending_deposits");
+/// cw20 left behind by force-closed orders, keyed by (maker, cw20 contract), until the
+/// maker claims it
+pub const STRANDED: Map<(&Addr, &Addr), Uint128> = Map::new("stranded");
+/// Block height of each maker's latest open, tracked while a cooldown is configured
+pub const LAST_OPEN: Map<&Addr, u64> = Map::new("last_open");
+/// Amount of each native denom or cw20 address that changed hands in fills, both sides
+/// counted
+pub const VOLUME: Map<&str, Uint128> = Map::new("volume");
+/// Price history of closed single-asset orders, keyed by (maker denom, taker denom)
+pub const TWAP: Map<(&str, &str), TwapState> = Map::new("twap");
+
+pub struct OrderIndexes<'a> {
+    /// Keyed by (maker address, order key)
+    pub maker: MultiIndex<'a, (Vec<u8>, Vec<u8>), Order>,
+    pub group: GroupIndex<'a>,
+}
+
+/// (maker address, group id, order key)
+type GroupKey = (Vec<u8>, Vec<u8>, Vec<u8>);
+
+/// Index of grouped orders keyed by (maker address, group id, order key). Ungrouped
+/// orders are not indexed, and groups of different makers never share a prefix.
+pub struct GroupIndex<'a> {
+    idx: Map<'a, GroupKey, u64>,
+}
+
+impl<'a> GroupIndex<'a> {
+    pub const fn new(idx_namespace: &'a str) -> Self {
+        GroupIndex {
+            idx: Map::new(idx_namespace),
+        }
+    }
+
+    /// Ids of the maker's orders tagged with the group id, in ascending id order
+    pub fn order_ids<'c>(
+        &self,
+        store: &'c dyn Storage,
+        maker: &Addr,
+        group_id: &str,
+        start_after: Option<u64>,
+    ) -> Box<dyn Iterator<Item = StdResult<u64>> + 'c> {
+        let start = start_after.map(|id| Bound::exclusive(U64Key::from(id)));
+        let ids = self
+            .idx
+            .prefix((maker.as_bytes().to_vec(), group_id.as_bytes().to_vec()))
+            .range(store, start, None, cosmwasm_std::Order::Ascending)
+            .map(|item| item.map(|(_, id)| id));
+        Box::new(ids)
+    }
+
+    fn key(order: &Order, pk: &[u8]) -> Option<GroupKey> {
+        order.group_id.as_ref().map(|group_id| {
+            (
+                order.maker_address.as_bytes().to_vec(),
+                group_id.as_bytes().to_vec(),
+                pk.to_vec(),
+            )
+        })
+    }
+}
+
+impl<'a> Index<Order> for GroupIndex<'a> {
+    fn save(&self, store: &mut dyn Storage, pk: &[u8], data: &Order) -> StdResult<()> {
+        match GroupIndex::key(data, pk) {
+            Some(key) => {
+                let id = u64::from_be_bytes(
+                    <[u8; 8]>::try_from(pk)
+                        .map_err(|_| StdError::generic_err("Order keys are 8 bytes"))?,
+                );
+                self.idx.save(store, key, &id)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn remove(&self, store: &mut dyn Storage, pk: &[u8], old_data: &Order) -> StdResult<()> {
+        if let Some(key) = GroupIndex::key(old_data, pk) {
+            self.idx.remove(store, key);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> IndexList<Order> for OrderIndexes<'a> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<Order>> + '_> {
+        let v: Vec<&dyn Index<Order>> = vec![&self.maker, &self.group];
+        Box::new(v.into_iter())
+    }
+}
+
+pub fn orders<'a>() -> IndexedMap<'a, U64Key, Order, OrderIndexes<'a>> {
+    let indexes = OrderIndexes {
+        maker: MultiIndex::new(
+            |order, pk| (order.maker_address.as_bytes().to_vec(), pk),
+            "orders",
+            "orders__maker",
+        ),
+        group: GroupIndex::new("orders__group"),
+    };
+    IndexedMap::new("orders", indexes)
+}
 
 pub fn next_id(store: &mut dyn Storage) -> StdResult<u64> {
     let id: u64 = ORDER_COUNT.may_load(store)?.unwrap_or_default() + 1;